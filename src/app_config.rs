@@ -1,11 +1,579 @@
+use crate::client::LATEST_TRANSACTIONS_LEN;
+use crate::hold::MAX_HOLD_TTL;
+use crate::statement::MAX_STATEMENT_LEN;
+use mongodb::options::ServerAddress;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use tracing_subscriber::EnvFilter;
+
+pub const DEFAULT_CONFIG_PATH: &str = "rinha.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PersistenceMode {
+    Cache,
+    Atomic,
+    Transactional,
+}
+
+impl FromStr for PersistenceMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "cache" => Ok(PersistenceMode::Cache),
+            "atomic" => Ok(PersistenceMode::Atomic),
+            "transactional" => Ok(PersistenceMode::Transactional),
+            _ => Err(String::from(
+                "expected one of 'cache', 'atomic' or 'transactional'",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyMode {
+    Off,
+    Report,
+    Repair,
+}
+
+impl FromStr for VerifyMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(VerifyMode::Off),
+            "report" => Ok(VerifyMode::Report),
+            "repair" => Ok(VerifyMode::Repair),
+            _ => Err(String::from("expected one of 'off', 'report' or 'repair'")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OtelProtocol {
+    Grpc,
+    Http,
+}
+
+impl FromStr for OtelProtocol {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "grpc" => Ok(OtelProtocol::Grpc),
+            "http" => Ok(OtelProtocol::Http),
+            _ => Err(String::from("expected one of 'grpc' or 'http'")),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Could not read config file '{0}': {1}")]
+    Read(String, std::io::Error),
+
+    #[error("Invalid config file '{0}': {1}")]
+    Parse(String, toml::de::Error),
+
+    #[error("Invalid value '{1}' for {0}: {2}")]
+    Env(&'static str, String, String),
+
+    #[error("Invalid {0}: {1} (set it in the config file or through {2})")]
+    Invalid(&'static str, String, &'static str),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub mongodb_url: String,
-    pub socket_path: String,
+    pub mongodb_database: String,
+    pub mongodb_min_pool_size: u32,
+    pub socket_path: Option<String>,
+    pub socket_mode: u32,
+    pub listen_addrs: Vec<SocketAddr>,
+    pub tls_listen_addrs: Vec<SocketAddr>,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub metrics_listen_addr: Option<SocketAddr>,
+    pub shm_prefix: String,
+    pub journal_path: String,
+    pub persistence_mode: PersistenceMode,
+    pub idempotency_ttl: u64,
+    pub hold_ttl: u64,
+    pub latest_transactions_len: usize,
+    pub shutdown_timeout: u64,
+    pub persistence_queue_size: usize,
+    pub persistence_batch_size: usize,
+    pub readiness_max_backlog: usize,
+    pub client_rate_limit: f64,
+    pub client_rate_burst: u32,
+    pub global_rate_limit: f64,
+    pub global_rate_burst: u32,
+    pub persistence_max_retries: u32,
+    pub persistence_retry_delay_ms: u64,
+    pub dead_letter_path: String,
+    pub dead_letter_retry_interval: u64,
+    pub warm_up_cache: bool,
+    pub verify_balances: VerifyMode,
+    pub reconcile_interval: u64,
+    pub reconcile_report_path: Option<String>,
+    pub log_level: String,
+    pub otel_endpoint: Option<String>,
+    pub otel_protocol: OtelProtocol,
+    pub otel_service_name: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mongodb_url: String::new(),
+            mongodb_database: String::from("rinha"),
+            mongodb_min_pool_size: 3,
+            socket_path: None,
+            socket_mode: 0o777,
+            listen_addrs: Vec::new(),
+            tls_listen_addrs: Vec::new(),
+            tls_cert_path: None,
+            tls_key_path: None,
+            metrics_listen_addr: None,
+            shm_prefix: String::from("dk-rinha-2024"),
+            journal_path: String::from("rinha.journal"),
+            persistence_mode: PersistenceMode::Cache,
+            idempotency_ttl: 86400,
+            hold_ttl: 604800,
+            latest_transactions_len: LATEST_TRANSACTIONS_LEN,
+            shutdown_timeout: 30,
+            persistence_queue_size: 4096,
+            persistence_batch_size: 256,
+            readiness_max_backlog: 3072,
+            client_rate_limit: 0.0,
+            client_rate_burst: 20,
+            global_rate_limit: 0.0,
+            global_rate_burst: 1000,
+            persistence_max_retries: 5,
+            persistence_retry_delay_ms: 100,
+            dead_letter_path: String::from("rinha.deadletter"),
+            dead_letter_retry_interval: 30,
+            warm_up_cache: false,
+            verify_balances: VerifyMode::Off,
+            reconcile_interval: 0,
+            reconcile_report_path: None,
+            log_level: String::from("info"),
+            otel_endpoint: None,
+            otel_protocol: OtelProtocol::Grpc,
+            otel_service_name: String::from("rinha"),
+        }
+    }
+}
+
+/// Loads the config file named by `CONFIG_PATH` (or `rinha.toml` when it
+/// exists), applies the environment on top of it and validates the result.
+pub fn config() -> Result<Config, ConfigError> {
+    let mut config = match std::env::var("CONFIG_PATH") {
+        Ok(path) => Config::from_file(&path)?,
+        Err(_) if std::path::Path::new(DEFAULT_CONFIG_PATH).exists() => {
+            Config::from_file(DEFAULT_CONFIG_PATH)?
+        }
+        Err(_) => Config::default(),
+    };
+
+    config.apply_env()?;
+    config.validate()?;
+
+    Ok(config)
+}
+
+impl Config {
+    fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| ConfigError::Read(path.to_string(), err))?;
+
+        toml::from_str(&contents).map_err(|err| ConfigError::Parse(path.to_string(), err))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        override_with(&mut self.mongodb_url, "MONGODB_URL")?;
+        override_with(&mut self.mongodb_database, "MONGODB_DATABASE")?;
+        override_with(&mut self.mongodb_min_pool_size, "MONGODB_MIN_POOL_SIZE")?;
+        override_with(&mut self.shm_prefix, "SHM_PREFIX")?;
+        override_with(&mut self.journal_path, "JOURNAL_PATH")?;
+        override_with(&mut self.persistence_mode, "PERSISTENCE_MODE")?;
+        override_with(&mut self.idempotency_ttl, "IDEMPOTENCY_TTL")?;
+        override_with(&mut self.hold_ttl, "HOLD_TTL")?;
+        override_with(&mut self.latest_transactions_len, "LATEST_TRANSACTIONS_LEN")?;
+        override_with(&mut self.shutdown_timeout, "SHUTDOWN_TIMEOUT")?;
+        override_with(&mut self.persistence_queue_size, "PERSISTENCE_QUEUE_SIZE")?;
+        override_with(&mut self.persistence_batch_size, "PERSISTENCE_BATCH_SIZE")?;
+        override_with(&mut self.readiness_max_backlog, "READINESS_MAX_BACKLOG")?;
+        override_with(&mut self.client_rate_limit, "CLIENT_RATE_LIMIT")?;
+        override_with(&mut self.client_rate_burst, "CLIENT_RATE_BURST")?;
+        override_with(&mut self.global_rate_limit, "GLOBAL_RATE_LIMIT")?;
+        override_with(&mut self.global_rate_burst, "GLOBAL_RATE_BURST")?;
+        override_with(&mut self.persistence_max_retries, "PERSISTENCE_MAX_RETRIES")?;
+        override_with(
+            &mut self.persistence_retry_delay_ms,
+            "PERSISTENCE_RETRY_DELAY_MS",
+        )?;
+        override_with(&mut self.dead_letter_path, "DEAD_LETTER_PATH")?;
+        override_with(
+            &mut self.dead_letter_retry_interval,
+            "DEAD_LETTER_RETRY_INTERVAL",
+        )?;
+        override_with(&mut self.warm_up_cache, "WARM_UP_CACHE")?;
+        override_with(&mut self.verify_balances, "VERIFY_BALANCES")?;
+        override_with(&mut self.reconcile_interval, "RECONCILE_INTERVAL")?;
+        override_with(&mut self.log_level, "LOG_LEVEL")?;
+        override_with(&mut self.otel_protocol, "OTEL_PROTOCOL")?;
+        override_with(&mut self.otel_service_name, "OTEL_SERVICE_NAME")?;
+
+        if let Some(socket_path) = env("SOCKET_PATH")? {
+            self.socket_path = Some(socket_path);
+        }
+
+        if let Some(tls_cert_path) = env("TLS_CERT_PATH")? {
+            self.tls_cert_path = Some(tls_cert_path);
+        }
+
+        if let Some(tls_key_path) = env("TLS_KEY_PATH")? {
+            self.tls_key_path = Some(tls_key_path);
+        }
+
+        if let Some(metrics_listen_addr) = env("METRICS_LISTEN_ADDR")? {
+            self.metrics_listen_addr = Some(metrics_listen_addr);
+        }
+
+        if let Some(otel_endpoint) = env("OTEL_ENDPOINT")? {
+            self.otel_endpoint = Some(otel_endpoint);
+        }
+
+        if let Some(reconcile_report_path) = env("RECONCILE_REPORT_PATH")? {
+            self.reconcile_report_path = Some(reconcile_report_path);
+        }
+
+        if let Some(listen_addrs) = env_list("LISTEN_ADDRS")? {
+            self.listen_addrs = listen_addrs;
+        }
+
+        if let Some(tls_listen_addrs) = env_list("TLS_LISTEN_ADDRS")? {
+            self.tls_listen_addrs = tls_listen_addrs;
+        }
+
+        // Permissions are always written in octal, with or without the prefix.
+        if let Ok(value) = std::env::var("SOCKET_MODE") {
+            self.socket_mode = u32::from_str_radix(value.trim_start_matches("0o"), 8)
+                .map_err(|err| ConfigError::Env("SOCKET_MODE", value, err.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.mongodb_url.is_empty() {
+            return Err(ConfigError::Invalid(
+                "mongodb_url",
+                String::from("missing, expected the host:port of the MongoDB server"),
+                "MONGODB_URL",
+            ));
+        }
+
+        if let Err(err) = ServerAddress::parse(&self.mongodb_url) {
+            return Err(ConfigError::Invalid(
+                "mongodb_url",
+                format!("'{}' is not a host:port ({err})", self.mongodb_url),
+                "MONGODB_URL",
+            ));
+        }
+
+        if self.mongodb_database.is_empty() {
+            return Err(ConfigError::Invalid(
+                "mongodb_database",
+                String::from("must not be empty"),
+                "MONGODB_DATABASE",
+            ));
+        }
+
+        if self.socket_path.is_none()
+            && self.listen_addrs.is_empty()
+            && self.tls_listen_addrs.is_empty()
+        {
+            return Err(ConfigError::Invalid(
+                "listeners",
+                String::from("no unix socket, TCP or TLS address is configured"),
+                "SOCKET_PATH, LISTEN_ADDRS or TLS_LISTEN_ADDRS",
+            ));
+        }
+
+        if self.socket_path.as_deref() == Some("") {
+            return Err(ConfigError::Invalid(
+                "socket_path",
+                String::from("must not be empty"),
+                "SOCKET_PATH",
+            ));
+        }
+
+        if !self.tls_listen_addrs.is_empty() {
+            if self.tls_cert_path.is_none() {
+                return Err(ConfigError::Invalid(
+                    "tls_cert_path",
+                    String::from("TLS listeners need the path of a PEM certificate chain"),
+                    "TLS_CERT_PATH",
+                ));
+            }
+
+            if self.tls_key_path.is_none() {
+                return Err(ConfigError::Invalid(
+                    "tls_key_path",
+                    String::from("TLS listeners need the path of a PEM private key"),
+                    "TLS_KEY_PATH",
+                ));
+            }
+        }
+
+        let mut seen = HashSet::new();
+
+        if let Some(addr) = self
+            .listen_addrs
+            .iter()
+            .chain(&self.tls_listen_addrs)
+            .chain(&self.metrics_listen_addr)
+            .find(|addr| !seen.insert(*addr))
+        {
+            return Err(ConfigError::Invalid(
+                "listeners",
+                format!("{addr} is configured more than once"),
+                "LISTEN_ADDRS, TLS_LISTEN_ADDRS or METRICS_LISTEN_ADDR",
+            ));
+        }
+
+        if self.socket_mode > 0o777 {
+            return Err(ConfigError::Invalid(
+                "socket_mode",
+                format!(
+                    "{:o} is not a permission mode, expected at most 777",
+                    self.socket_mode
+                ),
+                "SOCKET_MODE",
+            ));
+        }
+
+        // The prefix ends up in shm and semaphore names, which are limited to
+        // a single path component.
+        if self.shm_prefix.is_empty()
+            || self.shm_prefix.len() > 64
+            || !self
+                .shm_prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(ConfigError::Invalid(
+                "shm_prefix",
+                format!(
+                    "'{}' must have between 1 and 64 alphanumeric characters, '-' or '_'",
+                    self.shm_prefix
+                ),
+                "SHM_PREFIX",
+            ));
+        }
+
+        if self.journal_path.is_empty() {
+            return Err(ConfigError::Invalid(
+                "journal_path",
+                String::from("must not be empty"),
+                "JOURNAL_PATH",
+            ));
+        }
+
+        if self.idempotency_ttl == 0 {
+            return Err(ConfigError::Invalid(
+                "idempotency_ttl",
+                String::from("must be a positive number of seconds"),
+                "IDEMPOTENCY_TTL",
+            ));
+        }
+
+        if !(1..=MAX_HOLD_TTL).contains(&self.hold_ttl) {
+            return Err(ConfigError::Invalid(
+                "hold_ttl",
+                format!(
+                    "{} is out of range, expected between 1 and {MAX_HOLD_TTL} seconds",
+                    self.hold_ttl
+                ),
+                "HOLD_TTL",
+            ));
+        }
+
+        if !(1..=MAX_STATEMENT_LEN).contains(&self.latest_transactions_len) {
+            return Err(ConfigError::Invalid(
+                "latest_transactions_len",
+                format!(
+                    "{} is out of range, expected between 1 and {MAX_STATEMENT_LEN}",
+                    self.latest_transactions_len
+                ),
+                "LATEST_TRANSACTIONS_LEN",
+            ));
+        }
+
+        if self.persistence_queue_size == 0 {
+            return Err(ConfigError::Invalid(
+                "persistence_queue_size",
+                String::from("must be a positive number of writes"),
+                "PERSISTENCE_QUEUE_SIZE",
+            ));
+        }
+
+        if self.persistence_batch_size == 0 {
+            return Err(ConfigError::Invalid(
+                "persistence_batch_size",
+                String::from("must be a positive number of writes"),
+                "PERSISTENCE_BATCH_SIZE",
+            ));
+        }
+
+        if self.readiness_max_backlog == 0
+            || self.readiness_max_backlog > self.persistence_queue_size
+        {
+            return Err(ConfigError::Invalid(
+                "readiness_max_backlog",
+                format!(
+                    "must be between 1 and persistence_queue_size ({})",
+                    self.persistence_queue_size
+                ),
+                "READINESS_MAX_BACKLOG",
+            ));
+        }
+
+        for (name, rate, burst, env) in [
+            (
+                "client_rate_limit",
+                self.client_rate_limit,
+                self.client_rate_burst,
+                "CLIENT_RATE_LIMIT or CLIENT_RATE_BURST",
+            ),
+            (
+                "global_rate_limit",
+                self.global_rate_limit,
+                self.global_rate_burst,
+                "GLOBAL_RATE_LIMIT or GLOBAL_RATE_BURST",
+            ),
+        ] {
+            if !rate.is_finite() || rate < 0.0 || (rate > 0.0 && burst == 0) {
+                return Err(ConfigError::Invalid(
+                    name,
+                    format!("{rate} requests per second with a burst of {burst}, expected a non-negative rate (0 disables it) and a positive burst"),
+                    env,
+                ));
+            }
+        }
+
+        if self.dead_letter_path.is_empty() || self.dead_letter_path == self.journal_path {
+            return Err(ConfigError::Invalid(
+                "dead_letter_path",
+                String::from("must be set and differ from journal_path"),
+                "DEAD_LETTER_PATH",
+            ));
+        }
+
+        if self.dead_letter_retry_interval == 0 {
+            return Err(ConfigError::Invalid(
+                "dead_letter_retry_interval",
+                String::from("must be a positive number of seconds"),
+                "DEAD_LETTER_RETRY_INTERVAL",
+            ));
+        }
+
+        if self
+            .reconcile_report_path
+            .as_ref()
+            .is_some_and(|path| path.is_empty())
+        {
+            return Err(ConfigError::Invalid(
+                "reconcile_report_path",
+                String::from("must not be empty"),
+                "RECONCILE_REPORT_PATH",
+            ));
+        }
+
+        if let Err(err) = EnvFilter::try_new(&self.log_level) {
+            return Err(ConfigError::Invalid(
+                "log_level",
+                err.to_string(),
+                "LOG_LEVEL",
+            ));
+        }
+
+        if let Some(endpoint) = &self.otel_endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                return Err(ConfigError::Invalid(
+                    "otel_endpoint",
+                    format!("'{endpoint}' must be an http:// or https:// URL"),
+                    "OTEL_ENDPOINT",
+                ));
+            }
+        }
+
+        if self.otel_service_name.is_empty() {
+            return Err(ConfigError::Invalid(
+                "otel_service_name",
+                String::from("must not be empty"),
+                "OTEL_SERVICE_NAME",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn env<T>(name: &'static str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|err: T::Err| ConfigError::Env(name, value, err.to_string())),
+        Err(_) => Ok(None),
+    }
 }
 
-pub fn config() -> Config {
-    Config {
-        mongodb_url: std::env::var("MONGODB_URL").unwrap(),
-        socket_path: std::env::var("SOCKET_PATH").unwrap(),
+// Lists come from the environment as comma separated values.
+fn env_list<T>(name: &'static str) -> Result<Option<Vec<T>>, ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                item.parse().map_err(|err: T::Err| {
+                    ConfigError::Env(name, item.to_string(), err.to_string())
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(Some),
+        Err(_) => Ok(None),
     }
 }
+
+fn override_with<T>(field: &mut T, name: &'static str) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = env(name)? {
+        *field = value;
+    }
+
+    Ok(())
+}
@@ -1,34 +1,208 @@
-use axum::{http::StatusCode, response::IntoResponse};
+use crate::client::Status;
+use axum::{
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum AppError {
     #[error("Cliente {0} não encontrado")]
     ClientNotFound(i32),
 
+    #[error("Cliente {0} já existe")]
+    ClientAlreadyExists(i32),
+
+    #[error("Transação {0} não encontrada")]
+    TransactionNotFound(String),
+
+    #[error("Transação {0} já foi estornada")]
+    AlreadyReversed(String),
+
+    #[error("Transação {0} faz parte de uma transferência e não pode ser estornada")]
+    TransferNotReversible(String),
+
+    #[error("Reserva {0} não encontrada")]
+    HoldNotFound(String),
+
+    #[error("Valor da captura deve ser positivo e não exceder o valor reservado")]
+    InvalidCapture,
+
+    #[error("Cliente {0} já possui o máximo de {1} reservas ativas")]
+    TooManyHolds(i32, usize),
+
     #[error("Saldo insuficiente")]
     InsufficientBalanceError,
 
+    #[error("Valor excede o limite suportado")]
+    Overflow,
+
+    #[error("Conta do cliente {0} está {1}")]
+    AccountNotActive(i32, Status),
+
+    #[error("Limite não cobre o saldo atual")]
+    InvalidLimit,
+
+    #[error("Cliente {0} possui saldo diferente de zero")]
+    NonZeroBalance(i32),
+
+    #[error("Campo 'janela_extrato' não é suportado no modo de persistência atômico")]
+    WindowNotSupported,
+
+    #[error("Origem e destino da transferência devem ser diferentes")]
+    InvalidTransfer,
+
+    #[error(
+        "Cabeçalho 'Idempotency-Key' deve ter entre 1 e 64 caracteres alfanuméricos, '-' ou '_'."
+    )]
+    InvalidIdempotencyKey,
+
+    #[error("Chave de idempotência já utilizada com outra transação")]
+    IdempotencyKeyConflict,
+
+    #[error("Parâmetro '{0}' inválido")]
+    InvalidQueryParam(&'static str),
+
+    #[error("Serviço sobrecarregado, tente novamente")]
+    Overloaded,
+
+    #[error("Limite de requisições excedido, tente novamente em {0}s")]
+    RateLimited(u64),
+
     #[error(transparent)]
     MongoError(#[from] mongodb::error::Error),
 
     #[error(transparent)]
     DeError(#[from] serde_json::error::Error),
+
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+
+    #[error(transparent)]
+    BsonError(#[from] mongodb::bson::ser::Error),
+}
+
+/// Left on error responses so middleware can tell which variant produced them.
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorVariant(pub &'static str);
+
+impl AppError {
+    pub fn variant(&self) -> &'static str {
+        match self {
+            AppError::ClientNotFound(..) => "ClientNotFound",
+            AppError::ClientAlreadyExists(..) => "ClientAlreadyExists",
+            AppError::TransactionNotFound(..) => "TransactionNotFound",
+            AppError::AlreadyReversed(..) => "AlreadyReversed",
+            AppError::TransferNotReversible(..) => "TransferNotReversible",
+            AppError::HoldNotFound(..) => "HoldNotFound",
+            AppError::InvalidCapture => "InvalidCapture",
+            AppError::TooManyHolds(..) => "TooManyHolds",
+            AppError::InsufficientBalanceError => "InsufficientBalanceError",
+            AppError::Overflow => "Overflow",
+            AppError::AccountNotActive(..) => "AccountNotActive",
+            AppError::InvalidLimit => "InvalidLimit",
+            AppError::NonZeroBalance(..) => "NonZeroBalance",
+            AppError::WindowNotSupported => "WindowNotSupported",
+            AppError::InvalidTransfer => "InvalidTransfer",
+            AppError::InvalidIdempotencyKey => "InvalidIdempotencyKey",
+            AppError::IdempotencyKeyConflict => "IdempotencyKeyConflict",
+            AppError::InvalidQueryParam(..) => "InvalidQueryParam",
+            AppError::Overloaded => "Overloaded",
+            AppError::RateLimited(..) => "RateLimited",
+            AppError::MongoError(..) => "MongoError",
+            AppError::DeError(..) => "DeError",
+            AppError::IoError(..) => "IoError",
+            AppError::BsonError(..) => "BsonError",
+        }
+    }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
         let message = self.to_string();
+        let variant = ErrorVariant(self.variant());
 
-        match &self {
+        let mut response = match &self {
             AppError::ClientNotFound(_) => (StatusCode::NOT_FOUND, message).into_response(),
 
+            AppError::ClientAlreadyExists(_) => (StatusCode::CONFLICT, message).into_response(),
+
+            AppError::TransactionNotFound(_) => (StatusCode::NOT_FOUND, message).into_response(),
+
+            AppError::AlreadyReversed(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+
+            AppError::TransferNotReversible(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+
+            AppError::HoldNotFound(_) => (StatusCode::NOT_FOUND, message).into_response(),
+
+            AppError::InvalidCapture => (StatusCode::UNPROCESSABLE_ENTITY, message).into_response(),
+
+            AppError::TooManyHolds(..) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+
             AppError::InsufficientBalanceError => {
                 (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
             }
 
+            AppError::Overflow => (StatusCode::UNPROCESSABLE_ENTITY, message).into_response(),
+
+            AppError::AccountNotActive(_, _) => (StatusCode::LOCKED, message).into_response(),
+
+            AppError::InvalidLimit => (StatusCode::UNPROCESSABLE_ENTITY, message).into_response(),
+
+            AppError::NonZeroBalance(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+
+            AppError::WindowNotSupported => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+
+            AppError::InvalidTransfer => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+
+            AppError::InvalidIdempotencyKey => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+
+            AppError::IdempotencyKeyConflict => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+
+            AppError::InvalidQueryParam(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+            }
+
+            AppError::Overloaded => (StatusCode::SERVICE_UNAVAILABLE, message).into_response(),
+
+            AppError::RateLimited(retry_after) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                message,
+            )
+                .into_response(),
+
             AppError::MongoError(_) => (StatusCode::INTERNAL_SERVER_ERROR, message).into_response(),
 
             AppError::DeError(_) => (StatusCode::UNPROCESSABLE_ENTITY, message).into_response(),
+
+            AppError::IoError(_) => (StatusCode::INTERNAL_SERVER_ERROR, message).into_response(),
+
+            AppError::BsonError(_) => (StatusCode::INTERNAL_SERVER_ERROR, message).into_response(),
+        };
+
+        // Client errors are part of the API; these are the ones worth a look.
+        if response.status().is_server_error() {
+            tracing::error!(error = %self, details = ?self, variant = variant.0, "request failed");
         }
+
+        response.extensions_mut().insert(variant);
+
+        response
     }
 }
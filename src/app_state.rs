@@ -1,47 +1,433 @@
-use crate::app_config::Config;
+use crate::app_config::{Config, PersistenceMode, VerifyMode};
 use crate::app_error::AppError;
-use crate::client::Client;
-use crate::transaction::TransactionDTO;
+use crate::client::{Client, Status};
+use crate::currency;
+use crate::dead_letter::{DeadLetter, DeadLetters};
+use crate::history::{encode_cursor, older_than, HistoryDTO, HistoryQuery};
+use crate::hold::{self, active_count_expr, held_expr, Hold};
+use crate::idempotency::{IdempotencyRecord, RECORDS_PER_STRIPE, STRIPE_BYTES};
+use crate::journal::Journal;
+use crate::logging::MongoCommands;
+use crate::metrics::Metrics;
+use crate::money::Money;
+use crate::persistence::{
+    insert_new, is_duplicate_key, is_transient, Batch, PendingWrite, PersistenceQueue,
+};
+use crate::rate_limit::RateLimiter;
+use crate::reconcile;
+use crate::statement::{StatementDTO, StatementQuery};
+use crate::transaction::{new_id, Kind, Transaction, TransactionDTO};
+use crate::transfer::{TransferDTO, TransferLeg};
 use crate::utils::{Cache, Semaphore};
-use mongodb::bson::doc;
-use mongodb::options::{ClientOptions, ServerAddress};
-use std::sync::Arc;
+use mongodb::bson::{doc, oid::ObjectId, to_bson, Document};
+use mongodb::error::{TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
+use mongodb::event::command::CommandEventHandler;
+use mongodb::options::{
+    ClientOptions, FindOneAndUpdateOptions, FindOptions, IndexOptions, ReturnDocument,
+    ServerAddress,
+};
+use mongodb::{ClientSession, IndexModel};
+use speedy::{LittleEndian, Writable};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use tokio::task::JoinHandle;
+
+/// Longest wait between two attempts of a MongoDB write.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
 
 pub struct AppState {
+    pub mongodb: mongodb::Client,
     pub db: mongodb::Database,
     pub cache: Cache,
     pub named_semaphore: Semaphore,
+    pub journal: Journal,
+    pub persistence_mode: PersistenceMode,
+    pub idempotency_ttl: u64,
+    pub hold_ttl: u64,
+    pub latest_transactions_len: usize,
+    pub persistence: PersistenceQueue,
+    pub persistence_batch_size: usize,
+    pub readiness_max_backlog: usize,
+    pub persistence_max_retries: u32,
+    pub persistence_retry_delay: Duration,
+    pub dead_letters: DeadLetters,
+    pub dead_letter_retry_interval: Duration,
+    pub metrics: Metrics,
+    pub rate_limiter: RateLimiter,
+    worker: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl AppState {
     pub async fn new(config: &Config) -> Arc<Self> {
+        let metrics = Metrics::new();
+
+        let mongodb = Self::connect(config, Some(metrics.mongodb_handler())).await;
+
+        let db = mongodb.default_database().unwrap();
+
+        let journal = Journal::open(&config.journal_path)
+            .await
+            .expect("Could not open journal!");
+
+        journal
+            .replay(&db)
+            .await
+            .expect("Could not replay journal!");
+
+        let dead_letters = DeadLetters::open(&config.dead_letter_path)
+            .await
+            .expect("Could not open dead letter file!");
+
+        let ttl_index = IndexModel::builder()
+            .keys(doc! { "created_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(config.idempotency_ttl))
+                    .build(),
+            )
+            .build();
+
+        db.collection::<IdempotencyRecord>("idempotency_keys")
+            .create_index(ttl_index, None)
+            .await
+            .expect("Could not create idempotency TTL index!");
+
+        db.collection::<Transaction>("transactions")
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "client": 1, "date": -1 })
+                    .build(),
+                None,
+            )
+            .await
+            .expect("Could not create transactions index!");
+
+        db.collection::<Transaction>("transactions")
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "reverses": 1 })
+                    .options(IndexOptions::builder().unique(true).sparse(true).build())
+                    .build(),
+                None,
+            )
+            .await
+            .expect("Could not create reversals index!");
+
+        let (persistence, receiver) = PersistenceQueue::new(config.persistence_queue_size);
+
+        let app_state = Arc::new(Self {
+            mongodb,
+            db,
+            cache: Cache::new(&config.shm_prefix),
+            named_semaphore: Semaphore::new(&config.shm_prefix, metrics.semaphore_wait.clone()),
+            journal,
+            persistence_mode: config.persistence_mode,
+            idempotency_ttl: config.idempotency_ttl,
+            hold_ttl: config.hold_ttl,
+            latest_transactions_len: config.latest_transactions_len,
+            persistence,
+            persistence_batch_size: config.persistence_batch_size,
+            readiness_max_backlog: config.readiness_max_backlog,
+            persistence_max_retries: config.persistence_max_retries,
+            persistence_retry_delay: Duration::from_millis(config.persistence_retry_delay_ms),
+            dead_letters,
+            dead_letter_retry_interval: Duration::from_secs(config.dead_letter_retry_interval),
+            metrics,
+            rate_limiter: RateLimiter::new(config),
+            worker: Mutex::new(None),
+        });
+
+        let worker = tokio::spawn(app_state.clone().run_persistence(receiver));
+
+        *app_state.worker.lock().unwrap() = Some(worker);
+
+        if config.verify_balances != VerifyMode::Off {
+            let mut repair = config.verify_balances == VerifyMode::Repair;
+
+            // Writes still waiting for MongoDB are not in the ledger yet, and
+            // repairing would take them back out of the balances.
+            let pending = app_state.journal.pending().await;
+            let failed = app_state.dead_letters.outstanding();
+
+            if repair && (pending > 0 || failed > 0) {
+                tracing::warn!(
+                    pending,
+                    failed,
+                    "writes are still missing from MongoDB, balances are only reported"
+                );
+
+                repair = false;
+            }
+
+            let report = reconcile::run(
+                &app_state.db,
+                reconcile::window(config.persistence_mode, config.latest_transactions_len),
+                None,
+                repair,
+            )
+            .await
+            .expect("Could not verify balances!");
+
+            tracing::info!(
+                clients = report.clients,
+                discrepancies = report.discrepancies.len(),
+                unverified = report.unverified,
+                repaired = report.repaired,
+                report = %serde_json::to_string(&report).unwrap(),
+                "balance verification finished"
+            );
+        }
+
+        // Runs after the verification so a repaired balance is what gets cached.
+        if config.warm_up_cache && config.persistence_mode != PersistenceMode::Atomic {
+            app_state
+                .warm_up_cache()
+                .await
+                .expect("Could not warm up cache!");
+        }
+
+        if config.reconcile_interval > 0 {
+            tokio::spawn(app_state.clone().run_reconciliation(
+                Duration::from_secs(config.reconcile_interval),
+                config.reconcile_report_path.clone(),
+            ));
+        }
+
+        app_state
+    }
+
+    pub async fn connect(
+        config: &Config,
+        command_event_handler: Option<Arc<dyn CommandEventHandler>>,
+    ) -> mongodb::Client {
+        let command_event_handler: Arc<dyn CommandEventHandler> =
+            Arc::new(MongoCommands::new(command_event_handler));
+
         let opts = ClientOptions::builder()
-            .min_pool_size(3)
+            .min_pool_size(config.mongodb_min_pool_size)
+            .command_event_handler(command_event_handler)
             .hosts(vec![ServerAddress::parse(&config.mongodb_url).unwrap()])
-            .default_database(String::from("rinha"))
+            .default_database(config.mongodb_database.clone())
             .build();
 
         let mongodb = mongodb::Client::with_options(opts).unwrap();
 
         mongodb.warm_connection_pool().await;
 
-        Arc::new(Self {
-            db: mongodb.default_database().unwrap(),
-            cache: Cache::new(),
-            named_semaphore: Semaphore::new(),
-        })
+        mongodb
+    }
+
+    async fn warm_up_cache(&self) -> Result<(), AppError> {
+        let mut cursor = self
+            .db
+            .collection::<Client>("clients")
+            .find(None, None)
+            .await?;
+
+        while cursor.advance().await? {
+            let client = cursor.deserialize_current()?;
+
+            self.cache.insert(&client._id.to_string(), &client).await;
+        }
+
+        Ok(())
+    }
+
+    // A run is skipped while this instance still has writes on their way to
+    // MongoDB, since they would all show up as drift. Other instances' writes
+    // and the ones landing mid-run can't be seen from here, so a discrepancy
+    // is only reported once two runs in a row found it.
+    async fn run_reconciliation(self: Arc<Self>, period: Duration, report_path: Option<String>) {
+        let mut interval = tokio::time::interval(period);
+        let mut seen = HashSet::new();
+
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            if self.persistence.len() > 0 || self.dead_letters.outstanding() > 0 {
+                continue;
+            }
+
+            let cache = (self.persistence_mode != PersistenceMode::Atomic)
+                .then_some((&self.cache, &self.named_semaphore));
+
+            let report = match reconcile::run(
+                &self.db,
+                reconcile::window(self.persistence_mode, self.latest_transactions_len),
+                cache,
+                false,
+            )
+            .await
+            {
+                Ok(mut report) => {
+                    report.confirm(&mut seen);
+
+                    report
+                }
+                Err(err) => {
+                    tracing::error!(error = %err, details = ?err, "reconciliation failed");
+
+                    continue;
+                }
+            };
+
+            let written = match &report_path {
+                Some(path) => report.write(path).await,
+                None => Ok(()),
+            };
+
+            tracing::info!(
+                clients = report.clients,
+                discrepancies = report.discrepancies.len(),
+                unverified = report.unverified,
+                report = %serde_json::to_string(&report).unwrap(),
+                "reconciliation finished"
+            );
+
+            if let Err(err) = written {
+                tracing::error!(
+                    error = %err,
+                    path = report_path.as_deref(),
+                    "could not write reconciliation report"
+                );
+            }
+        }
+    }
+
+    /// Stops the persistence queue and waits for the worker to empty it until
+    /// the deadline, returning how many writes were left behind. Those are
+    /// still in the journal and get replayed on the next startup.
+    pub async fn drain(&self, deadline: Duration) -> usize {
+        self.persistence.close();
+
+        let Some(worker) = self.worker.lock().unwrap().take() else {
+            return 0;
+        };
+
+        match tokio::time::timeout(deadline, worker).await {
+            Ok(_) => 0,
+            Err(_) => self.persistence.len(),
+        }
+    }
+
+    async fn run_persistence(self: Arc<Self>, mut receiver: Receiver<PendingWrite>) {
+        let mut retry_dead_letters = tokio::time::interval(self.dead_letter_retry_interval);
+
+        loop {
+            let write = tokio::select! {
+                write = receiver.recv() => write,
+                _ = retry_dead_letters.tick() => {
+                    if receiver.is_empty() && self.dead_letters.outstanding() > 0 {
+                        self.replay_dead_letters().await;
+                    }
+
+                    continue;
+                }
+                _ = self.persistence.closed() => {
+                    // No new writes from here on, but the ones already queued
+                    // are still received below.
+                    receiver.close();
+
+                    continue;
+                }
+            };
+
+            let Some(write) = write else {
+                break;
+            };
+
+            let mut batch = Batch::default();
+
+            batch.push(write);
+
+            while batch.writes < self.persistence_batch_size {
+                match receiver.try_recv() {
+                    Ok(write) => batch.push(write),
+                    Err(_) => break,
+                }
+            }
+
+            self.flush(batch).await;
+        }
+    }
+
+    // Dead letters only carry client ids, so the snapshot written for them is
+    // whatever the cache holds now. That only happens with an empty queue,
+    // otherwise an older snapshot still waiting there would land after it.
+    async fn replay_dead_letters(&self) {
+        let dead_letters = match self.dead_letters.take().await {
+            Ok(dead_letters) => dead_letters,
+            Err(err) => {
+                tracing::error!(error = %err, details = ?err, "could not read dead letter file");
+
+                return;
+            }
+        };
+
+        // A letter that can't be rebuilt is recorded again and the rest are
+        // still replayed.
+        'letters: for dead_letter in dead_letters {
+            let mut batch = Batch {
+                transactions: dead_letter.transactions.clone(),
+                clients: HashMap::new(),
+                seqs: dead_letter.seqs.clone(),
+                idempotency_records: dead_letter.idempotency_records.clone(),
+                writes: dead_letter.writes,
+            };
+
+            if self.persistence_mode != PersistenceMode::Atomic {
+                for id in &dead_letter.clients {
+                    match self.get_client(*id).await {
+                        Ok(client) => {
+                            batch.clients.insert(*id, client);
+                        }
+                        Err(err) => {
+                            tracing::error!(
+                                error = %err,
+                                details = ?err,
+                                client = id,
+                                transactions = ?transaction_ids(&dead_letter.transactions),
+                                seqs = ?dead_letter.seqs,
+                                "could not load client to replay dead letter"
+                            );
+
+                            if let Err(err) = self.dead_letters.record(&dead_letter).await {
+                                tracing::error!(
+                                    error = %err,
+                                    details = ?err,
+                                    transactions = ?transaction_ids(&dead_letter.transactions),
+                                    seqs = ?dead_letter.seqs,
+                                    "could not record dead letter, writes are left to the journal replay"
+                                );
+                            }
+
+                            continue 'letters;
+                        }
+                    }
+                }
+            }
+
+            self.flush(batch).await;
+        }
     }
 
     pub async fn update_client_balance(
         &self,
-        id: i32,
-        transaction: &TransactionDTO,
-    ) -> Result<Client, AppError> {
-        let key = id.to_string();
+        transaction: &Transaction,
+    ) -> Result<(Client, u64), AppError> {
+        if self.persistence_mode == PersistenceMode::Atomic {
+            return self.atomic_update_client_balance(transaction).await;
+        }
+
+        let key = transaction.client.to_string();
 
         self.named_semaphore.wait(&key).await;
 
-        let result = self._update_client_balance(id, transaction, &key).await;
+        let result = self._update_client_balance(transaction, &key).await;
 
         self.named_semaphore.release(&key).await;
 
@@ -50,10 +436,11 @@ impl AppState {
 
     async fn _update_client_balance(
         &self,
-        id: i32,
-        transaction: &TransactionDTO,
+        transaction: &Transaction,
         key: &str,
-    ) -> Result<Client, AppError> {
+    ) -> Result<(Client, u64), AppError> {
+        let id = transaction.client;
+
         let mut client = match self.cache.get(key).await {
             None => self
                 .db
@@ -64,42 +451,1335 @@ impl AppState {
             Some(client) => Ok(client),
         }?;
 
-        client.update(transaction)?;
+        client.update(&transaction.clone().into(), self.latest_transactions_len)?;
+
+        let seq = self.journal.append(transaction, Some(&client)).await?;
 
         self.cache.insert(key, &client).await;
 
-        Ok(client)
+        Ok((client, seq))
     }
 
-    pub async fn get_client(&self, id: i32) -> Result<Client, AppError> {
+    async fn atomic_update_client_balance(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<(Client, u64), AppError> {
+        let client = self
+            .atomic_apply(transaction.client, &transaction.clone().into(), None)
+            .await?;
+
+        let seq = self.atomic_journal(transaction, None).await?;
+
+        Ok((client, seq))
+    }
+
+    // MongoDB already applied the update when the journal entry is written,
+    // so a failed append takes it back and the request fails without a trace.
+    async fn atomic_journal(
+        &self,
+        transaction: &Transaction,
+        hold: Option<&Hold>,
+    ) -> Result<u64, AppError> {
+        let err = match self.journal.append(transaction, None).await {
+            Ok(seq) => return Ok(seq),
+            Err(err) => err,
+        };
+
+        if let Err(undo_err) = self.atomic_undo(transaction, hold).await {
+            tracing::error!(
+                error = %undo_err,
+                details = ?undo_err,
+                client = transaction.client,
+                transaction = transaction._id,
+                "could not undo atomic update after the journal append failed"
+            );
+        }
+
+        Err(err)
+    }
+
+    // The entry `$slice` pushed out of the window is not put back; statements
+    // asking for more than the window still find it in the ledger.
+    async fn atomic_undo(
+        &self,
+        transaction: &Transaction,
+        hold: Option<&Hold>,
+    ) -> Result<(), AppError> {
+        let value = match transaction.kind {
+            Kind::C => transaction.value.checked_neg()?,
+            Kind::D => transaction.value,
+        };
+
+        let mut inc = Document::new();
+
+        inc.insert(
+            currency::balance_field(transaction.currency.as_deref()),
+            value,
+        );
+
+        let mut update = doc! {
+            "$inc": inc,
+            "$pull": { "latest_transactions": { "id": &transaction._id } },
+        };
+
+        if let Some(hold) = hold {
+            update.insert("$push", doc! { "holds": to_bson(hold)? });
+        }
+
+        self.db
+            .collection::<Client>("clients")
+            .update_one(doc! { "_id": transaction.client }, update, None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn atomic_apply(
+        &self,
+        id: i32,
+        transaction: &TransactionDTO,
+        hold_id: Option<&str>,
+    ) -> Result<Client, AppError> {
+        let (filter, update) = self.atomic_update(id, transaction, hold_id)?;
+
+        let opts = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        match self
+            .db
+            .collection::<Client>("clients")
+            .find_one_and_update(filter, update, opts)
+            .await?
+        {
+            Some(client) => Ok(client),
+            None => Err(self.atomic_rejection(id, transaction, hold_id).await),
+        }
+    }
+
+    // Filter and update applying the transaction in a single conditional
+    // write; it matches nothing when one of the rules would reject it.
+    fn atomic_update(
+        &self,
+        id: i32,
+        transaction: &TransactionDTO,
+        hold_id: Option<&str>,
+    ) -> Result<(Document, Document), AppError> {
+        let now = hold::now();
+
+        let value = match transaction.kind {
+            Kind::C => transaction.value,
+            Kind::D => transaction.value.checked_neg()?,
+        };
+
+        // Documents written before statuses existed have no field at all,
+        // which both operators treat as an active account.
+        let status = match transaction.kind {
+            Kind::C => doc! { "$ne": to_bson(&Status::Closed)? },
+            Kind::D => doc! { "$nin": [to_bson(&Status::Frozen)?, to_bson(&Status::Closed)?] },
+        };
+
+        let currency = transaction.currency.as_deref();
+        let balance_field = currency::balance_field(currency);
+        let limit_field = currency::limit_field(currency);
+
+        // Accounts in other currencies are created by their first movement,
+        // so a missing balance or limit counts as zero.
+        let balance = doc! { "$ifNull": [format!("${balance_field}"), 0] };
+        let limit = doc! { "$ifNull": [format!("${limit_field}"), 0] };
+
+        // Debits must leave room for every other unexpired hold, while a
+        // capture spends the funds its own hold already set aside.
+        let available = match (&transaction.kind, currency) {
+            (Kind::D, None) => {
+                doc! { "$subtract": [{ "$add": [&balance, value] }, held_expr(&now, hold_id)] }
+            }
+            _ => doc! { "$add": [&balance, value] },
+        };
+
+        let mut guards = vec![doc! { "$gte": [available, { "$multiply": [limit, -1] }] }];
+
+        // MongoDB would refuse the $inc on overflow anyway; checking up front
+        // lets the local replay below report it as such.
+        if transaction.kind == Kind::C {
+            guards.push(doc! { "$lte": [balance, Money::MAX.checked_sub(value)?] });
+        }
+
+        let mut filter = doc! {
+            "_id": id,
+            "status": status,
+            "$expr": { "$and": guards },
+        };
+
+        let mut inc = Document::new();
+
+        inc.insert(balance_field, value);
+
+        let mut update = doc! {
+            "$inc": inc,
+            "$push": {
+                "latest_transactions": {
+                    "$each": [to_bson(transaction)?],
+                    "$position": 0,
+                    // $slice only takes a literal; per-client windows are
+                    // refused at creation in this mode.
+                    "$slice": self.latest_transactions_len as i32,
+                },
+            },
+        };
+
+        if let Some(hold_id) = hold_id {
+            filter.insert(
+                "holds",
+                doc! {
+                    "$elemMatch": {
+                        "id": hold_id,
+                        "expira_em": { "$gt": &now },
+                        "valor": { "$gte": transaction.value },
+                    },
+                },
+            );
+
+            update.insert("$pull", doc! { "holds": { "id": hold_id } });
+        }
+
+        Ok((filter, update))
+    }
+
+    // Replaying the rules locally tells which guard rejected an atomic update.
+    async fn atomic_rejection(
+        &self,
+        id: i32,
+        transaction: &TransactionDTO,
+        hold_id: Option<&str>,
+    ) -> AppError {
+        let mut client = match self
+            .db
+            .collection::<Client>("clients")
+            .find_one(doc! { "_id": id }, None)
+            .await
+        {
+            Ok(Some(client)) => client,
+            Ok(None) => return AppError::ClientNotFound(id),
+            Err(err) => return err.into(),
+        };
+
+        if let Some(hold_id) = hold_id {
+            match client.take_hold(hold_id) {
+                Ok(hold) if hold.value < transaction.value => return AppError::InvalidCapture,
+                Ok(_) => {}
+                Err(err) => return err,
+            }
+        }
+
+        match client.update(transaction, self.latest_transactions_len) {
+            Ok(_) => AppError::InsufficientBalanceError,
+            Err(err) => err,
+        }
+    }
+
+    // Both legs and their ledger rows go in one MongoDB transaction, so a
+    // transfer either moves the money and is in the ledger or leaves no
+    // trace. Like the transactional mode, this needs a replica set.
+    async fn atomic_transfer(
+        &self,
+        debit: &Transaction,
+        credit: &Transaction,
+    ) -> Result<(Client, Client), AppError> {
+        let (debit_filter, debit_update) =
+            self.atomic_update(debit.client, &debit.clone().into(), None)?;
+        let (credit_filter, credit_update) =
+            self.atomic_update(credit.client, &credit.clone().into(), None)?;
+
+        let clients = self.db.collection::<Client>("clients");
+        let transactions = self.db.collection::<Transaction>("transactions");
+
+        let opts = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let mut session = self.mongodb.start_session(None).await?;
+        let mut attempt = 0;
+
+        loop {
+            session.start_transaction(None).await?;
+
+            let result = async {
+                let Some(from) = clients
+                    .find_one_and_update_with_session(
+                        debit_filter.clone(),
+                        debit_update.clone(),
+                        opts.clone(),
+                        &mut session,
+                    )
+                    .await?
+                else {
+                    return Ok(Err(debit));
+                };
+
+                let Some(to) = clients
+                    .find_one_and_update_with_session(
+                        credit_filter.clone(),
+                        credit_update.clone(),
+                        opts.clone(),
+                        &mut session,
+                    )
+                    .await?
+                else {
+                    return Ok(Err(credit));
+                };
+
+                transactions
+                    .insert_many_with_session([debit, credit], None, &mut session)
+                    .await?;
+
+                Ok::<_, mongodb::error::Error>(Ok((from, to)))
+            }
+            .await;
+
+            let err = match result {
+                Ok(Ok(clients)) => match self.commit_transaction(&mut session).await {
+                    Ok(()) => return Ok(clients),
+                    Err(err) => err,
+                },
+                Ok(Err(rejected)) => {
+                    abort_transaction(&mut session).await;
+
+                    return Err(self
+                        .atomic_rejection(rejected.client, &rejected.clone().into(), None)
+                        .await);
+                }
+                Err(err) => {
+                    abort_transaction(&mut session).await;
+
+                    err
+                }
+            };
+
+            if !err.contains_label(TRANSIENT_TRANSACTION_ERROR) {
+                return Err(err.into());
+            }
+
+            let Some(delay) = self.retry_delay(attempt) else {
+                return Err(err.into());
+            };
+
+            tokio::time::sleep(delay).await;
+
+            attempt += 1;
+        }
+    }
+
+    pub async fn transfer(&self, transfer: &TransferDTO) -> Result<[TransferLeg; 2], AppError> {
+        if transfer.from == transfer.to {
+            return Err(AppError::InvalidTransfer);
+        }
+
+        // Both locks are always taken lowest id first, so two opposite
+        // transfers between the same pair of clients cannot deadlock.
+        let (first, second) = match transfer.from < transfer.to {
+            true => (transfer.from.to_string(), transfer.to.to_string()),
+            false => (transfer.to.to_string(), transfer.from.to_string()),
+        };
+
+        self.named_semaphore.wait(&first).await;
+        self.named_semaphore.wait(&second).await;
+
+        let result = self._transfer(transfer).await;
+
+        self.named_semaphore.release(&second).await;
+        self.named_semaphore.release(&first).await;
+
+        result
+    }
+
+    async fn _transfer(&self, transfer: &TransferDTO) -> Result<[TransferLeg; 2], AppError> {
+        let transfer_id = new_id();
+        let date = TransferDTO::date();
+
+        let debit =
+            Transaction::new(transfer.from, transfer.debit(&date)).with_transfer(&transfer_id);
+        let credit =
+            Transaction::new(transfer.to, transfer.credit(&date)).with_transfer(&transfer_id);
+
+        if self.persistence_mode == PersistenceMode::Atomic {
+            let (from, to) = self.atomic_transfer(&debit, &credit).await?;
+
+            return Ok([
+                TransferLeg {
+                    transaction: debit,
+                    client: from,
+                    seq: None,
+                },
+                TransferLeg {
+                    transaction: credit,
+                    client: to,
+                    seq: None,
+                },
+            ]);
+        }
+
+        let from_key = transfer.from.to_string();
+        let to_key = transfer.to.to_string();
+
+        let mut from = self.load_client(transfer.from, &from_key).await?;
+        let mut to = self.load_client(transfer.to, &to_key).await?;
+
+        from.update(&debit.clone().into(), self.latest_transactions_len)?;
+        to.update(&credit.clone().into(), self.latest_transactions_len)?;
+
+        let seq = self
+            .journal
+            .append_transfer(&debit, &from, &credit, &to)
+            .await?;
+
+        self.cache.insert(&from_key, &from).await;
+        self.cache.insert(&to_key, &to).await;
+
+        Ok([
+            TransferLeg {
+                transaction: debit,
+                client: from,
+                seq: Some(seq),
+            },
+            TransferLeg {
+                transaction: credit,
+                client: to,
+                seq: Some(seq),
+            },
+        ])
+    }
+
+    pub async fn reverse_transaction(
+        &self,
+        id: i32,
+        transaction_id: &str,
+    ) -> Result<(Client, Transaction, u64), AppError> {
         let key = id.to_string();
 
         self.named_semaphore.wait(&key).await;
 
-        let result = self._get_client(id, &key).await;
+        let result = self._reverse_transaction(id, transaction_id, &key).await;
 
         self.named_semaphore.release(&key).await;
 
         result
     }
 
-    async fn _get_client(&self, id: i32, key: &str) -> Result<Client, AppError> {
-        match self.cache.get(key).await {
-            None => match self
-                .db
-                .collection::<Client>("clients")
-                .find_one(doc! { "_id": id }, None)
-                .await?
-                .ok_or(AppError::ClientNotFound(id))
-            {
-                Ok(client) => {
-                    self.cache.insert(key, &client).await;
+    async fn _reverse_transaction(
+        &self,
+        id: i32,
+        transaction_id: &str,
+        key: &str,
+    ) -> Result<(Client, Transaction, u64), AppError> {
+        let original = self.find_transaction(id, transaction_id, key).await?;
 
-                    Ok(client)
+        // Reversing one leg would leave the other side of the transfer
+        // standing, so transfers can't be reversed.
+        if original.transfer.is_some() {
+            return Err(AppError::TransferNotReversible(transaction_id.to_string()));
+        }
+
+        let reversal = original.reversal();
+
+        let transactions = self.db.collection::<Transaction>("transactions");
+
+        // The reversal row is stored first: the unique index on `reverses`
+        // lets only one reversal through, across instances too, and the row
+        // is taken out again if the balance can't be updated.
+        match transactions.insert_one(&reversal, None).await {
+            Ok(_) => {}
+            Err(err) if is_duplicate_key(&err) => {
+                return Err(AppError::AlreadyReversed(transaction_id.to_string()));
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        let result = match self.persistence_mode {
+            PersistenceMode::Atomic => self.atomic_update_client_balance(&reversal).await,
+            _ => self._update_client_balance(&reversal, key).await,
+        };
+
+        match result {
+            Ok((client, seq)) => Ok((client, reversal, seq)),
+            Err(err) => {
+                if let Err(err) = transactions
+                    .delete_one(doc! { "reverses": transaction_id }, None)
+                    .await
+                {
+                    tracing::error!(
+                        transaction = transaction_id,
+                        error = %err,
+                        "could not remove rejected reversal"
+                    );
                 }
-                other => other,
-            },
-            Some(client) => Ok(client),
+
+                Err(err)
+            }
+        }
+    }
+
+    async fn find_transaction(
+        &self,
+        id: i32,
+        transaction_id: &str,
+        key: &str,
+    ) -> Result<Transaction, AppError> {
+        let client = self.load_client(id, key).await?;
+
+        // Entries still in the embedded window may not have reached the
+        // transactions collection yet, so they are checked first.
+        if let Some(transaction_dto) = client
+            .latest_transactions
+            .into_iter()
+            .find(|transaction| transaction.id.as_deref() == Some(transaction_id))
+        {
+            return Ok(Transaction {
+                _id: transaction_id.to_string(),
+                transfer: transaction_dto.transfer.clone(),
+                ..Transaction::new(id, transaction_dto)
+            });
         }
+
+        let Ok(oid) = ObjectId::parse_str(transaction_id) else {
+            return Err(AppError::TransactionNotFound(transaction_id.to_string()));
+        };
+
+        self.db
+            .collection::<Transaction>("transactions")
+            .find_one(doc! { "_id": oid, "client": id }, None)
+            .await?
+            .ok_or(AppError::TransactionNotFound(transaction_id.to_string()))
+    }
+
+    pub async fn place_hold(&self, id: i32, hold: Hold) -> Result<(Client, Option<u64>), AppError> {
+        let key = id.to_string();
+
+        self.named_semaphore.wait(&key).await;
+
+        let result = self._place_hold(id, hold, &key).await;
+
+        self.named_semaphore.release(&key).await;
+
+        result
+    }
+
+    async fn _place_hold(
+        &self,
+        id: i32,
+        hold: Hold,
+        key: &str,
+    ) -> Result<(Client, Option<u64>), AppError> {
+        if self.persistence_mode != PersistenceMode::Atomic {
+            let mut client = self.load_client(id, key).await?;
+
+            client.place_hold(hold)?;
+
+            let seq = self.snapshot_client(&client, key).await?;
+
+            return Ok((client, Some(seq)));
+        }
+
+        let now = hold::now();
+
+        let filter = doc! {
+            "_id": id,
+            "status": { "$nin": [to_bson(&Status::Frozen)?, to_bson(&Status::Closed)?] },
+            "$expr": {
+                "$and": [
+                    {
+                        "$gte": [
+                            { "$subtract": ["$balance", { "$add": [held_expr(&now, None), hold.value] }] },
+                            { "$multiply": ["$limit", -1] },
+                        ],
+                    },
+                    { "$lt": [active_count_expr(&now), hold::MAX_HOLDS as i64] },
+                ],
+            },
+        };
+
+        let update = doc! { "$push": { "holds": to_bson(&hold)? } };
+
+        let opts = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let collection = self.db.collection::<Client>("clients");
+
+        match collection.find_one_and_update(filter, update, opts).await? {
+            Some(client) => Ok((client, None)),
+            None => {
+                let mut client = self.load_client(id, key).await?;
+
+                client.place_hold(hold)?;
+
+                Err(AppError::InsufficientBalanceError)
+            }
+        }
+    }
+
+    pub async fn capture_hold(
+        &self,
+        id: i32,
+        hold_id: &str,
+        value: Option<Money>,
+    ) -> Result<(Client, Transaction, u64), AppError> {
+        let key = id.to_string();
+
+        self.named_semaphore.wait(&key).await;
+
+        let result = self._capture_hold(id, hold_id, value, &key).await;
+
+        self.named_semaphore.release(&key).await;
+
+        result
+    }
+
+    async fn _capture_hold(
+        &self,
+        id: i32,
+        hold_id: &str,
+        value: Option<Money>,
+        key: &str,
+    ) -> Result<(Client, Transaction, u64), AppError> {
+        let mut client = self.load_client(id, key).await?;
+
+        let hold = client.take_hold(hold_id)?;
+        let value = value.unwrap_or(hold.value);
+
+        if !value.is_positive() || value > hold.value {
+            return Err(AppError::InvalidCapture);
+        }
+
+        let transaction = Transaction::new(id, hold.capture(value));
+        let transaction_dto: TransactionDTO = transaction.clone().into();
+
+        if self.persistence_mode == PersistenceMode::Atomic {
+            let client = self
+                .atomic_apply(id, &transaction_dto, Some(hold_id))
+                .await?;
+
+            let seq = self.atomic_journal(&transaction, Some(&hold)).await?;
+
+            return Ok((client, transaction, seq));
+        }
+
+        client.update(&transaction_dto, self.latest_transactions_len)?;
+
+        let seq = self.journal.append(&transaction, Some(&client)).await?;
+
+        self.cache.insert(key, &client).await;
+
+        Ok((client, transaction, seq))
+    }
+
+    pub async fn release_hold(
+        &self,
+        id: i32,
+        hold_id: &str,
+    ) -> Result<(Client, Option<u64>), AppError> {
+        let key = id.to_string();
+
+        self.named_semaphore.wait(&key).await;
+
+        let result = self._release_hold(id, hold_id, &key).await;
+
+        self.named_semaphore.release(&key).await;
+
+        result
+    }
+
+    async fn _release_hold(
+        &self,
+        id: i32,
+        hold_id: &str,
+        key: &str,
+    ) -> Result<(Client, Option<u64>), AppError> {
+        let mut client = self.load_client(id, key).await?;
+
+        client.take_hold(hold_id)?;
+
+        if self.persistence_mode != PersistenceMode::Atomic {
+            let seq = self.snapshot_client(&client, key).await?;
+
+            return Ok((client, Some(seq)));
+        }
+
+        let opts = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        self.db
+            .collection::<Client>("clients")
+            .find_one_and_update(
+                doc! { "_id": id, "holds.id": hold_id },
+                doc! { "$pull": { "holds": { "id": hold_id } } },
+                opts,
+            )
+            .await?
+            .map(|client| (client, None))
+            .ok_or(AppError::HoldNotFound(hold_id.to_string()))
+    }
+
+    // Writes that exhaust their retries go to the dead letter file and keep
+    // their journal entries pending.
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            writes = batch.writes,
+            transactions = batch.transactions.len(),
+            clients = ?batch.clients.keys().collect::<Vec<_>>(),
+        )
+    )]
+    async fn flush(&self, batch: Batch) {
+        let writes = batch.writes;
+
+        self.persistence.writing(writes);
+
+        self._flush(batch).await;
+
+        self.persistence.written(writes);
+    }
+
+    async fn _flush(&self, batch: Batch) {
+        if let Err(err) = self.write_with_retry(&batch).await {
+            tracing::error!(
+                error = %err,
+                details = ?err,
+                transactions = ?transaction_ids(&batch.transactions),
+                seqs = ?batch.seqs,
+                "could not persist batch, moving it to the dead letter file"
+            );
+
+            if let Err(err) = self.dead_letters.record(&DeadLetter::from(&batch)).await {
+                tracing::error!(
+                    error = %err,
+                    details = ?err,
+                    transactions = ?transaction_ids(&batch.transactions),
+                    seqs = ?batch.seqs,
+                    "could not record dead letter, writes are left to the journal replay"
+                );
+            }
+
+            return;
+        }
+
+        if let Err(err) = self.journal.commit(&batch.seqs).await {
+            tracing::error!(
+                error = %err,
+                details = ?err,
+                seqs = ?batch.seqs,
+                "could not commit journal entries"
+            );
+        }
+    }
+
+    async fn write_with_retry(&self, batch: &Batch) -> Result<(), AppError> {
+        let mut attempt = 0;
+
+        loop {
+            match self.write_batch(batch).await {
+                Err(err) if is_transient(&err) => {
+                    let Some(delay) = self.retry_delay(attempt) else {
+                        return Err(err);
+                    };
+
+                    tracing::warn!(
+                        error = %err,
+                        attempt = attempt + 1,
+                        retry_in_ms = delay.as_millis() as u64,
+                        "transient error writing batch, retrying"
+                    );
+
+                    tokio::time::sleep(delay).await;
+
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Backoff shared by every retried MongoDB write: the delay before retry
+    // number `attempt`, or None once the retries are used up.
+    fn retry_delay(&self, attempt: u32) -> Option<Duration> {
+        (attempt < self.persistence_max_retries).then(|| {
+            self.persistence_retry_delay
+                .saturating_mul(2u32.saturating_pow(attempt))
+                .min(MAX_RETRY_DELAY)
+        })
+    }
+
+    async fn write_batch(&self, batch: &Batch) -> Result<(), AppError> {
+        let transactions = self.db.collection::<Transaction>("transactions");
+
+        match self.persistence_mode {
+            PersistenceMode::Cache => {
+                insert_new(&transactions, &batch.transactions).await?;
+
+                // The driver has no bulk write, so coalescing gets it down to
+                // one replace per client in the batch.
+                for client in batch.clients.values() {
+                    self.db
+                        .collection::<Client>("clients")
+                        .replace_one(doc! { "_id": client._id }, client, None)
+                        .await?;
+                }
+            }
+            PersistenceMode::Atomic => {
+                insert_new(&transactions, &batch.transactions).await?;
+            }
+            PersistenceMode::Transactional => {
+                self.flush_in_session(batch).await?;
+            }
+        }
+
+        insert_new(
+            &self.db.collection::<IdempotencyRecord>("idempotency_keys"),
+            &batch.idempotency_records,
+        )
+        .await
+    }
+
+    // A transient error aborts the transaction and is retried as a whole by
+    // `write_with_retry`, so both loops share one bound and backoff.
+    async fn flush_in_session(&self, batch: &Batch) -> Result<(), AppError> {
+        let transactions = self.db.collection::<Transaction>("transactions");
+        let clients = self.db.collection::<Client>("clients");
+
+        let mut session = self.mongodb.start_session(None).await?;
+
+        session.start_transaction(None).await?;
+
+        let result = async {
+            // A batch whose commit went through without an answer comes back
+            // as a dead letter, so rows already stored are left out instead of
+            // failing the whole transaction with a duplicate key.
+            let ids: Vec<ObjectId> = batch
+                .transactions
+                .iter()
+                .filter_map(|transaction| ObjectId::parse_str(&transaction._id).ok())
+                .collect();
+
+            let mut stored = HashSet::new();
+
+            if !ids.is_empty() {
+                let opts = FindOptions::builder().projection(doc! { "_id": 1 }).build();
+
+                let mut cursor = self
+                    .db
+                    .collection::<Document>("transactions")
+                    .find_with_session(doc! { "_id": { "$in": ids } }, opts, &mut session)
+                    .await?;
+
+                while cursor.advance(&mut session).await? {
+                    if let Ok(id) = cursor.current().get_object_id("_id") {
+                        stored.insert(id.to_hex());
+                    }
+                }
+            }
+
+            let missing: Vec<&Transaction> = batch
+                .transactions
+                .iter()
+                .filter(|transaction| !stored.contains(&transaction._id))
+                .collect();
+
+            if !missing.is_empty() {
+                transactions
+                    .insert_many_with_session(missing, None, &mut session)
+                    .await?;
+            }
+
+            for client in batch.clients.values() {
+                clients
+                    .replace_one_with_session(
+                        doc! { "_id": client._id },
+                        client,
+                        None,
+                        &mut session,
+                    )
+                    .await?;
+            }
+
+            Ok::<(), mongodb::error::Error>(())
+        }
+        .await;
+
+        if let Err(err) = result {
+            abort_transaction(&mut session).await;
+
+            return Err(err.into());
+        }
+
+        Ok(self.commit_transaction(&mut session).await?)
+    }
+
+    // A commit with an unknown outcome can be sent again; the server treats
+    // it as a no-op when the first one went through.
+    async fn commit_transaction(
+        &self,
+        session: &mut ClientSession,
+    ) -> Result<(), mongodb::error::Error> {
+        let mut attempt = 0;
+
+        loop {
+            match session.commit_transaction().await {
+                Err(err) if err.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) => {
+                    let Some(delay) = self.retry_delay(attempt) else {
+                        return Err(err);
+                    };
+
+                    tokio::time::sleep(delay).await;
+
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    pub async fn get_client(&self, id: i32) -> Result<Client, AppError> {
+        if self.persistence_mode == PersistenceMode::Atomic {
+            return self
+                .db
+                .collection::<Client>("clients")
+                .find_one(doc! { "_id": id }, None)
+                .await?
+                .ok_or(AppError::ClientNotFound(id));
+        }
+
+        let key = id.to_string();
+
+        self.named_semaphore.wait(&key).await;
+
+        let result = self._get_client(id, &key).await;
+
+        self.named_semaphore.release(&key).await;
+
+        result
+    }
+
+    async fn _get_client(&self, id: i32, key: &str) -> Result<Client, AppError> {
+        let cached = self.cache.get(key).await;
+
+        self.metrics
+            .cache_lookups
+            .with_label_values(&[if cached.is_some() { "hit" } else { "miss" }])
+            .inc();
+
+        match cached {
+            None => match self
+                .db
+                .collection::<Client>("clients")
+                .find_one(doc! { "_id": id }, None)
+                .await?
+                .ok_or(AppError::ClientNotFound(id))
+            {
+                Ok(client) => {
+                    self.cache.insert(key, &client).await;
+
+                    Ok(client)
+                }
+                other => other,
+            },
+            Some(client) => Ok(client),
+        }
+    }
+
+    async fn load_client(&self, id: i32, key: &str) -> Result<Client, AppError> {
+        match self.persistence_mode {
+            PersistenceMode::Atomic => self
+                .db
+                .collection::<Client>("clients")
+                .find_one(doc! { "_id": id }, None)
+                .await?
+                .ok_or(AppError::ClientNotFound(id)),
+            _ => self._get_client(id, key).await,
+        }
+    }
+
+    pub async fn create_client(&self, client: Client) -> Result<Client, AppError> {
+        let key = client._id.to_string();
+
+        self.named_semaphore.wait(&key).await;
+
+        let result = self._create_client(client, &key).await;
+
+        self.named_semaphore.release(&key).await;
+
+        result
+    }
+
+    async fn _create_client(&self, client: Client, key: &str) -> Result<Client, AppError> {
+        client.check_limit()?;
+
+        // Atomic updates trim the window with a literal `$slice`, so every
+        // client gets the configured one.
+        if self.persistence_mode == PersistenceMode::Atomic
+            && client.latest_transactions_len.is_some()
+        {
+            return Err(AppError::WindowNotSupported);
+        }
+
+        match self
+            .db
+            .collection::<Client>("clients")
+            .insert_one(&client, None)
+            .await
+        {
+            Err(err) if is_duplicate_key(&err) => {
+                return Err(AppError::ClientAlreadyExists(client._id))
+            }
+            other => other?,
+        };
+
+        if self.persistence_mode != PersistenceMode::Atomic {
+            self.cache.insert(key, &client).await;
+        }
+
+        Ok(client)
+    }
+
+    pub async fn update_client_limit(
+        &self,
+        id: i32,
+        limit: Money,
+        currency: Option<&str>,
+    ) -> Result<(Client, Option<u64>), AppError> {
+        let key = id.to_string();
+
+        self.named_semaphore.wait(&key).await;
+
+        let result = self._update_client_limit(id, limit, currency, &key).await;
+
+        self.named_semaphore.release(&key).await;
+
+        result
+    }
+
+    async fn _update_client_limit(
+        &self,
+        id: i32,
+        limit: Money,
+        currency: Option<&str>,
+        key: &str,
+    ) -> Result<(Client, Option<u64>), AppError> {
+        let mut client = self.load_client(id, key).await?;
+
+        client.update_limit(limit, currency)?;
+
+        if self.persistence_mode != PersistenceMode::Atomic {
+            let seq = self.snapshot_client(&client, key).await?;
+
+            return Ok((client, Some(seq)));
+        }
+
+        // In atomic mode MongoDB owns the balance, so the guard has to be
+        // re-evaluated there against concurrent debits.
+        let balance_field = currency::balance_field(currency);
+
+        let filter = doc! {
+            "_id": id,
+            "$expr": {
+                "$gte": [{ "$ifNull": [format!("${balance_field}"), 0] }, limit.checked_neg()?],
+            },
+        };
+
+        let mut set = Document::new();
+
+        set.insert(currency::limit_field(currency), limit);
+
+        let clients = self.db.collection::<Client>("clients");
+
+        let result = clients
+            .update_one(filter, doc! { "$set": set }, None)
+            .await?;
+
+        if result.matched_count == 0 {
+            return match clients.find_one(doc! { "_id": id }, None).await? {
+                Some(_) => Err(AppError::InvalidLimit),
+                None => Err(AppError::ClientNotFound(id)),
+            };
+        }
+
+        Ok((client, None))
+    }
+
+    // Client changes that are not transactions take the same way balances
+    // do: the cache and the journal now, MongoDB through the persistence
+    // queue, so they land in order with the snapshots already waiting there.
+    async fn snapshot_client(&self, client: &Client, key: &str) -> Result<u64, AppError> {
+        let seq = self.journal.snapshot(client).await?;
+
+        self.cache.insert(key, client).await;
+
+        Ok(seq)
+    }
+
+    pub async fn close_client(&self, id: i32) -> Result<(Client, Option<u64>), AppError> {
+        let key = id.to_string();
+
+        self.named_semaphore.wait(&key).await;
+
+        let result = self._close_client(id, &key).await;
+
+        self.named_semaphore.release(&key).await;
+
+        result
+    }
+
+    async fn _close_client(&self, id: i32, key: &str) -> Result<(Client, Option<u64>), AppError> {
+        let mut client = self.load_client(id, key).await?;
+
+        if client.has_funds() {
+            return Err(AppError::NonZeroBalance(id));
+        }
+
+        client.update_status(Status::Closed)?;
+
+        let filter = match self.persistence_mode {
+            PersistenceMode::Atomic => doc! { "_id": id, "balance": 0 },
+            _ => doc! { "_id": id },
+        };
+
+        let seq = self
+            .write_status(filter, &client, key)
+            .await
+            .map_err(|err| match err {
+                AppError::ClientNotFound(_) => AppError::NonZeroBalance(id),
+                other => other,
+            })?;
+
+        Ok((client, seq))
+    }
+
+    pub async fn update_client_status(
+        &self,
+        id: i32,
+        status: Status,
+    ) -> Result<(Client, Option<u64>), AppError> {
+        let key = id.to_string();
+
+        self.named_semaphore.wait(&key).await;
+
+        let result = self._update_client_status(id, status, &key).await;
+
+        self.named_semaphore.release(&key).await;
+
+        result
+    }
+
+    async fn _update_client_status(
+        &self,
+        id: i32,
+        status: Status,
+        key: &str,
+    ) -> Result<(Client, Option<u64>), AppError> {
+        let mut client = self.load_client(id, key).await?;
+
+        client.update_status(status)?;
+
+        let seq = self.write_status(doc! { "_id": id }, &client, key).await?;
+
+        Ok((client, seq))
+    }
+
+    // Only atomic mode writes the status straight to MongoDB, where the
+    // filter can still guard it; the other modes queue a snapshot.
+    async fn write_status(
+        &self,
+        filter: mongodb::bson::Document,
+        client: &Client,
+        key: &str,
+    ) -> Result<Option<u64>, AppError> {
+        if self.persistence_mode != PersistenceMode::Atomic {
+            return Ok(Some(self.snapshot_client(client, key).await?));
+        }
+
+        let result = self
+            .db
+            .collection::<Client>("clients")
+            .update_one(
+                filter,
+                doc! { "$set": { "status": to_bson(&client.status)? } },
+                None,
+            )
+            .await?;
+
+        if result.matched_count == 0 {
+            return Err(AppError::ClientNotFound(client._id));
+        }
+
+        Ok(None)
+    }
+
+    pub async fn get_idempotency_record(
+        &self,
+        id: i32,
+        key: &str,
+    ) -> Result<Option<IdempotencyRecord>, AppError> {
+        let record_id = IdempotencyRecord::record_id(id, key);
+
+        let cached = self
+            .cache
+            .get::<Vec<IdempotencyRecord>>(&IdempotencyRecord::stripe(&record_id))
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .find(|record| record._id == record_id);
+
+        let record = match cached {
+            Some(record) => Some(record),
+            None => {
+                self.db
+                    .collection::<IdempotencyRecord>("idempotency_keys")
+                    .find_one(doc! { "_id": &record_id }, None)
+                    .await?
+            }
+        };
+
+        Ok(record.filter(|record| !record.is_expired(self.idempotency_ttl)))
+    }
+
+    /// Keeps the record around until the persistence queue has stored it.
+    /// Callers hold the lock of its stripe.
+    pub async fn cache_idempotency_record(&self, record: &IdempotencyRecord) {
+        let stripe = IdempotencyRecord::stripe(&record._id);
+
+        let mut records = self
+            .cache
+            .get::<Vec<IdempotencyRecord>>(&stripe)
+            .await
+            .unwrap_or_default();
+
+        records
+            .retain(|cached| cached._id != record._id && !cached.is_expired(self.idempotency_ttl));
+
+        records.push(record.clone());
+
+        if records.len() > RECORDS_PER_STRIPE {
+            records.drain(..records.len() - RECORDS_PER_STRIPE);
+        }
+
+        let sizes: Vec<usize> = records
+            .iter()
+            .map(|cached| Writable::<LittleEndian>::bytes_needed(cached).unwrap_or(0))
+            .collect();
+
+        let mut bytes: usize = sizes.iter().sum();
+        let mut oldest = 0;
+
+        while bytes > STRIPE_BYTES && oldest + 1 < records.len() {
+            bytes -= sizes[oldest];
+            oldest += 1;
+        }
+
+        records.drain(..oldest);
+
+        self.cache.insert(&stripe, &records).await;
+    }
+
+    pub async fn get_statement(
+        &self,
+        id: i32,
+        query: &StatementQuery,
+    ) -> Result<StatementDTO, AppError> {
+        let mut client = self.get_client(id).await?;
+
+        let window = client.window(self.latest_transactions_len);
+        let size = query.size(window)?;
+
+        client.latest_transactions.truncate(size);
+
+        // The embedded window only holds the newest entries, anything past it
+        // has to come from the ledger, starting right after the oldest one. A
+        // window that is not full yet already has every entry there is.
+        if size > window {
+            let mut filter = doc! { "client": id };
+
+            if let Some(oldest) = client.latest_transactions.last() {
+                filter.extend(older_than(
+                    &oldest.date,
+                    oldest
+                        .id
+                        .as_deref()
+                        .and_then(|id| ObjectId::parse_str(id).ok()),
+                ));
+            }
+
+            let opts = FindOptions::builder()
+                .sort(doc! { "date": -1, "_id": -1 })
+                .limit((size - client.latest_transactions.len()) as i64)
+                .build();
+
+            let mut cursor = self
+                .db
+                .collection::<Transaction>("transactions")
+                .find(filter, opts)
+                .await?;
+
+            while cursor.advance().await? {
+                client
+                    .latest_transactions
+                    .push(cursor.deserialize_current()?.into());
+            }
+        }
+
+        Ok(client.into())
+    }
+
+    pub async fn get_transaction_history(
+        &self,
+        id: i32,
+        query: &HistoryQuery,
+    ) -> Result<HistoryDTO, AppError> {
+        let page_size = query.page_size()?;
+        let filter = query.filter(id)?;
+
+        self.get_client(id).await?;
+
+        let opts = FindOptions::builder()
+            .sort(doc! { "date": -1, "_id": -1 })
+            .limit(page_size + 1)
+            .build();
+
+        let mut cursor = self
+            .db
+            .collection::<Transaction>("transactions")
+            .find(filter, opts)
+            .await?;
+
+        let mut transactions = Vec::new();
+
+        while cursor.advance().await? {
+            transactions.push(cursor.deserialize_current()?);
+        }
+
+        let next_cursor = match transactions.len() as i64 > page_size {
+            true => {
+                transactions.truncate(page_size as usize);
+                transactions.last().map(encode_cursor)
+            }
+            false => None,
+        };
+
+        Ok(HistoryDTO {
+            transactions: transactions.into_iter().map(Transaction::into).collect(),
+            next_cursor,
+        })
+    }
+}
+
+fn transaction_ids(transactions: &[Transaction]) -> Vec<&str> {
+    transactions
+        .iter()
+        .map(|transaction| transaction._id.as_str())
+        .collect()
+}
+
+async fn abort_transaction(session: &mut ClientSession) {
+    if let Err(err) = session.abort_transaction().await {
+        tracing::warn!(error = %err, "could not abort transaction");
     }
 }
@@ -1,13 +1,21 @@
+use crate::money::Money;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BalanceDTO {
-    pub total: i32,
+    pub total: Money,
 
     #[serde(rename(serialize = "data_extrato"))]
     pub date: String,
 
     #[serde(rename(serialize = "limite"))]
-    pub limit: i32,
+    pub limit: Money,
+
+    #[serde(
+        rename(serialize = "moeda"),
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub currency: Option<String>,
 }
@@ -0,0 +1,60 @@
+use super::Status;
+use crate::{money::Money, statement::MAX_STATEMENT_LEN};
+use serde::{Deserialize, Deserializer};
+
+pub fn deserialize_id<'de, D>(deserializer: D) -> Result<i32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    if let Ok(value) = i32::deserialize(deserializer) {
+        if value > 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(serde::de::Error::custom(
+        "Campo 'id' deve ser um inteiro positivo.",
+    ))
+}
+
+pub fn deserialize_limit<'de, D>(deserializer: D) -> Result<Money, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    if let Ok(value) = Money::deserialize(deserializer) {
+        if value >= Money::ZERO {
+            return Ok(value);
+        }
+    }
+
+    Err(serde::de::Error::custom(
+        "Campo 'limite' deve ser um inteiro não negativo.",
+    ))
+}
+
+pub fn deserialize_window<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    if let Ok(value) = u32::deserialize(deserializer) {
+        if (1..=MAX_STATEMENT_LEN as u32).contains(&value) {
+            return Ok(Some(value));
+        }
+    }
+
+    Err(serde::de::Error::custom(
+        "Campo 'janela_extrato' deve ser um inteiro entre 1 e 100.",
+    ))
+}
+
+pub fn deserialize_status<'de, D>(deserializer: D) -> Result<Status, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Status::deserialize(deserializer) {
+        Ok(Status::Closed) | Err(_) => Err(serde::de::Error::custom(
+            "Campo 'situacao' deve ser 'ativa' ou 'congelada'.",
+        )),
+        Ok(value) => Ok(value),
+    }
+}
@@ -1,60 +1,221 @@
+mod deser;
 use chrono::{SecondsFormat, Utc};
 use serde::{Deserialize, Serialize};
 use speedy::{Readable, Writable};
+use std::collections::BTreeMap;
+use std::fmt::Display;
 
 use crate::{
     app_error::AppError,
     balance::BalanceDTO,
+    currency::CurrencyAccount,
+    hold::{self, Hold},
+    money::Money,
     statement::StatementDTO,
     transaction::{Kind, TransactionDTO, TransactionResponse},
 };
 
+pub const LATEST_TRANSACTIONS_LEN: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Readable, Writable)]
+pub enum Status {
+    #[default]
+    #[serde(rename = "ativa")]
+    Active,
+
+    #[serde(rename = "congelada")]
+    Frozen,
+
+    #[serde(rename = "encerrada")]
+    Closed,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Active => write!(f, "ativa"),
+            Status::Frozen => write!(f, "congelada"),
+            Status::Closed => write!(f, "encerrada"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Readable, Writable)]
 pub struct Client {
     pub _id: i32,
 
-    pub balance: i32,
+    pub balance: Money,
 
-    pub limit: i32,
+    pub limit: Money,
 
     pub latest_transactions: Vec<TransactionDTO>,
+
+    #[serde(default)]
+    pub status: Status,
+
+    #[serde(default)]
+    pub holds: Vec<Hold>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub currencies: BTreeMap<String, CurrencyAccount>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latest_transactions_len: Option<u32>,
+
+    /// Balance given at creation. Documents from before it was recorded have
+    /// none, and their default currency can't be checked against the ledger.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opening_balance: Option<Money>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientDTO {
+    #[serde(deserialize_with = "deser::deserialize_id")]
+    pub id: i32,
+
+    #[serde(
+        alias = "limite",
+        rename(serialize = "limite"),
+        deserialize_with = "deser::deserialize_limit"
+    )]
+    pub limit: Money,
+
+    #[serde(alias = "saldo", rename(serialize = "saldo"), default)]
+    pub balance: Money,
+
+    #[serde(rename(serialize = "situacao"), skip_deserializing)]
+    pub status: Status,
+
+    #[serde(
+        alias = "janela_extrato",
+        rename(serialize = "janela_extrato"),
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser::deserialize_window"
+    )]
+    pub latest_transactions_len: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusDTO {
+    #[serde(
+        alias = "situacao",
+        rename(serialize = "situacao"),
+        deserialize_with = "deser::deserialize_status"
+    )]
+    pub status: Status,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LimitDTO {
+    #[serde(
+        alias = "limite",
+        rename(serialize = "limite"),
+        deserialize_with = "deser::deserialize_limit"
+    )]
+    pub limit: Money,
+
+    #[serde(
+        alias = "moeda",
+        rename(serialize = "moeda"),
+        default,
+        deserialize_with = "crate::transaction::deser::deserialize_currency"
+    )]
+    pub currency: Option<String>,
+}
+
+impl From<ClientDTO> for Client {
+    fn from(client_dto: ClientDTO) -> Self {
+        Self {
+            _id: client_dto.id,
+            balance: client_dto.balance,
+            limit: client_dto.limit,
+            latest_transactions: Vec::new(),
+            status: Status::Active,
+            holds: Vec::new(),
+            currencies: BTreeMap::new(),
+            latest_transactions_len: client_dto.latest_transactions_len,
+            opening_balance: Some(client_dto.balance),
+        }
+    }
 }
 
-impl Into<StatementDTO> for Client {
-    fn into(self) -> StatementDTO {
-        StatementDTO {
+impl From<Client> for ClientDTO {
+    fn from(client: Client) -> Self {
+        Self {
+            id: client._id,
+            limit: client.limit,
+            balance: client.balance,
+            status: client.status,
+            latest_transactions_len: client.latest_transactions_len,
+        }
+    }
+}
+
+impl From<Client> for StatementDTO {
+    fn from(client: Client) -> Self {
+        let date = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+
+        Self {
             balance: BalanceDTO {
-                total: self.balance,
-                date: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
-                limit: self.limit,
+                total: client.balance,
+                date: date.clone(),
+                limit: client.limit,
+                currency: None,
             },
-            latest_transactions: self.latest_transactions,
+            latest_transactions: client.latest_transactions,
+            currency_balances: client
+                .currencies
+                .into_iter()
+                .map(|(currency, account)| BalanceDTO {
+                    total: account.balance,
+                    date: date.clone(),
+                    limit: account.limit,
+                    currency: Some(currency),
+                })
+                .collect(),
         }
     }
 }
 
-impl Into<TransactionResponse> for Client {
-    fn into(self) -> TransactionResponse {
-        TransactionResponse {
-            limit: self.limit,
-            balance: self.balance,
-        }
+impl From<Client> for TransactionResponse {
+    fn from(client: Client) -> Self {
+        client.response(None)
     }
 }
 
 impl Client {
-    pub fn update(&mut self, transaction: &TransactionDTO) -> Result<&mut Self, AppError> {
-        let value = match transaction.kind {
-            Kind::C => transaction.value,
-            Kind::D => -transaction.value,
+    pub fn update(
+        &mut self,
+        transaction: &TransactionDTO,
+        default_window: usize,
+    ) -> Result<&mut Self, AppError> {
+        match (&self.status, &transaction.kind) {
+            (Status::Active, _) | (Status::Frozen, Kind::C) => {}
+            (status, _) => return Err(AppError::AccountNotActive(self._id, status.clone())),
         };
 
-        self.balance += value;
+        self.release_expired_holds();
+
+        // Holds only ever reserve funds in the default currency.
+        let reserved = match (&transaction.kind, &transaction.currency) {
+            (Kind::D, None) => self.held()?,
+            _ => Money::ZERO,
+        };
+
+        let (balance, limit) = self.account_mut(transaction.currency.as_deref());
+
+        let updated = match transaction.kind {
+            Kind::C => balance.checked_add(transaction.value)?,
+            Kind::D => balance.checked_sub(transaction.value)?,
+        };
 
-        if self.balance < -self.limit {
+        if updated.checked_sub(reserved)? < limit.checked_neg()? {
             return Err(AppError::InsufficientBalanceError);
         };
 
+        *balance = updated;
+
         self.latest_transactions.insert(
             0,
             TransactionDTO {
@@ -62,13 +223,215 @@ impl Client {
                 kind: transaction.kind.clone(),
                 description: transaction.description.clone(),
                 date: transaction.date.clone(),
+                id: transaction.id.clone(),
+                currency: transaction.currency.clone(),
+                transfer: transaction.transfer.clone(),
             },
         );
 
-        if self.latest_transactions.len() > 10 {
-            self.latest_transactions.pop();
+        self.latest_transactions
+            .truncate(self.window(default_window));
+
+        Ok(self)
+    }
+
+    pub fn window(&self, default_window: usize) -> usize {
+        self.latest_transactions_len
+            .map_or(default_window, |len| len as usize)
+    }
+
+    pub fn update_limit(
+        &mut self,
+        limit: Money,
+        currency: Option<&str>,
+    ) -> Result<&mut Self, AppError> {
+        if self.status == Status::Closed {
+            return Err(AppError::AccountNotActive(self._id, Status::Closed));
+        }
+
+        let (balance, current_limit) = self.account_mut(currency);
+
+        if *balance < limit.checked_neg()? {
+            return Err(AppError::InvalidLimit);
+        }
+
+        *current_limit = limit;
+
+        Ok(self)
+    }
+
+    pub fn response(&self, currency: Option<&str>) -> TransactionResponse {
+        let (balance, limit) = match currency {
+            None => (self.balance, self.limit),
+            Some(currency) => self
+                .currencies
+                .get(currency)
+                .map(|account| (account.balance, account.limit))
+                .unwrap_or_default(),
         };
 
+        TransactionResponse {
+            balance,
+            limit,
+            id: None,
+            currency: currency.map(String::from),
+        }
+    }
+
+    pub fn has_funds(&self) -> bool {
+        !self.balance.is_zero()
+            || self
+                .currencies
+                .values()
+                .any(|account| !account.balance.is_zero())
+    }
+
+    fn account_mut(&mut self, currency: Option<&str>) -> (&mut Money, &mut Money) {
+        match currency {
+            None => (&mut self.balance, &mut self.limit),
+            Some(currency) => {
+                let account = self.currencies.entry(currency.to_string()).or_default();
+
+                (&mut account.balance, &mut account.limit)
+            }
+        }
+    }
+
+    pub fn check_limit(&self) -> Result<(), AppError> {
+        if self.balance < self.limit.checked_neg()? {
+            return Err(AppError::InvalidLimit);
+        }
+
+        Ok(())
+    }
+
+    pub fn update_status(&mut self, status: Status) -> Result<&mut Self, AppError> {
+        if self.status == Status::Closed {
+            return Err(AppError::AccountNotActive(self._id, Status::Closed));
+        }
+
+        self.status = status;
+
+        Ok(self)
+    }
+
+    pub fn held(&self) -> Result<Money, AppError> {
+        let now = hold::now();
+
+        Money::checked_sum(
+            self.holds
+                .iter()
+                .filter(|hold| hold.is_active(&now))
+                .map(|hold| hold.value),
+        )
+    }
+
+    pub fn place_hold(&mut self, hold: Hold) -> Result<&mut Self, AppError> {
+        if self.status != Status::Active {
+            return Err(AppError::AccountNotActive(self._id, self.status.clone()));
+        }
+
+        self.release_expired_holds();
+
+        if self.holds.len() >= hold::MAX_HOLDS {
+            return Err(AppError::TooManyHolds(self._id, hold::MAX_HOLDS));
+        }
+
+        let available = self
+            .balance
+            .checked_sub(self.held()?)?
+            .checked_sub(hold.value)?;
+
+        if available < self.limit.checked_neg()? {
+            return Err(AppError::InsufficientBalanceError);
+        }
+
+        self.holds.push(hold);
+
         Ok(self)
     }
+
+    pub fn take_hold(&mut self, hold_id: &str) -> Result<Hold, AppError> {
+        self.release_expired_holds();
+
+        match self.holds.iter().position(|hold| hold.id == hold_id) {
+            Some(index) => Ok(self.holds.remove(index)),
+            None => Err(AppError::HoldNotFound(hold_id.to_string())),
+        }
+    }
+
+    fn release_expired_holds(&mut self) {
+        let now = hold::now();
+
+        self.holds.retain(|hold| hold.is_active(&now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hold::{HoldDTO, MAX_HOLDS},
+        statement::MAX_STATEMENT_LEN,
+        transaction::new_id,
+        utils::Mmap,
+    };
+
+    // The cached snapshot has to fit in one shared memory entry with the
+    // widest window and as many holds as a client may have.
+    #[test]
+    fn largest_client_fits_in_the_cache() {
+        let mut client = Client::from(ClientDTO {
+            id: i32::MAX,
+            limit: Money::MAX,
+            balance: Money::ZERO,
+            status: Status::Active,
+            latest_transactions_len: Some(MAX_STATEMENT_LEN as u32),
+        });
+
+        client.latest_transactions = (0..MAX_STATEMENT_LEN)
+            .map(|_| TransactionDTO {
+                value: Money::MAX,
+                kind: Kind::D,
+                description: "descricao!".to_string(),
+                date: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+                id: Some(new_id()),
+                currency: Some("USD".to_string()),
+                transfer: Some(new_id()),
+            })
+            .collect();
+
+        for _ in 0..MAX_HOLDS {
+            let hold = Hold::new(
+                HoldDTO {
+                    value: Money::ZERO,
+                    description: "descricao!".to_string(),
+                    ttl: None,
+                },
+                hold::MAX_HOLD_TTL,
+            )
+            .unwrap();
+
+            client.place_hold(hold).unwrap();
+        }
+
+        let extra = Hold::new(
+            HoldDTO {
+                value: Money::ZERO,
+                description: "descricao!".to_string(),
+                ttl: None,
+            },
+            hold::MAX_HOLD_TTL,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            client.place_hold(extra),
+            Err(AppError::TooManyHolds(..))
+        ));
+
+        let bytes = client.write_to_vec().unwrap();
+
+        assert!(bytes.len() <= Mmap::MAX_LENGTH as usize);
+    }
 }
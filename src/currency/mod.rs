@@ -0,0 +1,28 @@
+use crate::money::Money;
+use serde::{Deserialize, Serialize};
+use speedy::{Readable, Writable};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Readable, Writable)]
+pub struct CurrencyAccount {
+    pub balance: Money,
+
+    pub limit: Money,
+}
+
+pub fn is_valid_code(code: &str) -> bool {
+    code.len() == 3 && code.chars().all(|c| c.is_ascii_uppercase())
+}
+
+pub fn balance_field(currency: Option<&str>) -> String {
+    match currency {
+        None => String::from("balance"),
+        Some(currency) => format!("currencies.{currency}.balance"),
+    }
+}
+
+pub fn limit_field(currency: Option<&str>) -> String {
+    match currency {
+        None => String::from("limit"),
+        Some(currency) => format!("currencies.{currency}.limit"),
+    }
+}
@@ -0,0 +1,106 @@
+use crate::{
+    app_error::AppError, idempotency::IdempotencyRecord, persistence::Batch,
+    transaction::Transaction,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+
+/// A batch MongoDB kept refusing. Clients are only referenced by id: by the
+/// time it is replayed the cache holds a newer snapshot than the one that
+/// failed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeadLetter {
+    pub transactions: Vec<Transaction>,
+    pub clients: Vec<i32>,
+    pub seqs: Vec<u64>,
+    pub idempotency_records: Vec<IdempotencyRecord>,
+    pub writes: usize,
+}
+
+impl From<&Batch> for DeadLetter {
+    fn from(batch: &Batch) -> Self {
+        Self {
+            transactions: batch.transactions.clone(),
+            clients: batch.clients.keys().copied().collect(),
+            seqs: batch.seqs.clone(),
+            idempotency_records: batch.idempotency_records.clone(),
+            writes: batch.writes,
+        }
+    }
+}
+
+/// Local file of writes that ran out of retries. Their journal entries stay
+/// uncommitted, so a restart restores them even if they are never replayed
+/// from here.
+pub struct DeadLetters {
+    path: String,
+    file: Mutex<File>,
+    outstanding: AtomicUsize,
+}
+
+impl DeadLetters {
+    // Only opened after the journal replay, which already covered anything
+    // left in the file by a previous run.
+    pub async fn open(path: &str) -> Result<Self, AppError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        file.set_len(0).await?;
+
+        Ok(Self {
+            path: path.to_string(),
+            file: Mutex::new(file),
+            outstanding: AtomicUsize::new(0),
+        })
+    }
+
+    pub async fn record(&self, dead_letter: &DeadLetter) -> Result<(), AppError> {
+        let mut line = serde_json::to_vec(dead_letter)?;
+
+        line.push(b'\n');
+
+        let mut file = self.file.lock().await;
+
+        file.write_all(&line).await?;
+        file.sync_data().await?;
+
+        self.outstanding
+            .fetch_add(dead_letter.writes, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Empties the file and hands back its contents. Whatever fails again has
+    /// to be recorded anew.
+    pub async fn take(&self) -> Result<Vec<DeadLetter>, AppError> {
+        let file = self.file.lock().await;
+
+        let mut lines = BufReader::new(File::open(&self.path).await?).lines();
+        let mut dead_letters = Vec::new();
+
+        while let Some(line) = lines.next_line().await? {
+            if let Ok(dead_letter) = serde_json::from_str::<DeadLetter>(&line) {
+                dead_letters.push(dead_letter);
+            }
+        }
+
+        file.set_len(0).await?;
+
+        self.outstanding.store(0, Ordering::SeqCst);
+
+        Ok(dead_letters)
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::SeqCst)
+    }
+}
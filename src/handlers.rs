@@ -1,57 +1,331 @@
 use crate::{
     app_error::AppError,
     app_state::AppState,
-    client::Client,
-    statement::StatementDTO,
+    client::{ClientDTO, LimitDTO, StatusDTO},
+    health::{self, HealthDTO},
+    history::{HistoryDTO, HistoryQuery},
+    hold::{CaptureDTO, Hold, HoldDTO},
+    idempotency::{idempotency_key, IdempotencyRecord},
+    persistence::{PendingWrite, PersistenceStatusDTO},
+    statement::{StatementDTO, StatementQuery},
     transaction::{Transaction, TransactionDTO, TransactionResponse},
+    transfer::{TransferDTO, TransferResponse},
 };
 use axum::{
     body::Bytes,
-    extract::{Path, State},
-    http::StatusCode,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
     Json,
 };
-use mongodb::bson::doc;
 use std::sync::Arc;
-use tokio::spawn;
 
 pub async fn transaction(
     app_state: State<Arc<AppState>>,
     Path(id): Path<i32>,
+    headers: HeaderMap,
     body: Bytes,
 ) -> Result<(StatusCode, Json<TransactionResponse>), AppError> {
     let transaction_dto = serde_json::from_slice::<TransactionDTO>(&body)?;
 
-    let client = app_state
-        .update_client_balance(id, &transaction_dto)
-        .await?;
+    let Some(key) = idempotency_key(&headers)? else {
+        let response = apply_transaction(app_state.0, id, transaction_dto, None).await?;
+
+        return Ok((StatusCode::OK, Json(response)));
+    };
+
+    let lock = IdempotencyRecord::stripe(&IdempotencyRecord::record_id(id, &key));
+
+    app_state.named_semaphore.wait(&lock).await;
+
+    let result = idempotent_transaction(app_state.0.clone(), id, &key, transaction_dto).await;
+
+    app_state.named_semaphore.release(&lock).await;
+
+    Ok((StatusCode::OK, Json(result?)))
+}
+
+async fn idempotent_transaction(
+    app_state: Arc<AppState>,
+    id: i32,
+    key: &str,
+    transaction_dto: TransactionDTO,
+) -> Result<TransactionResponse, AppError> {
+    match app_state.get_idempotency_record(id, key).await? {
+        Some(record) if record.matches(&transaction_dto) => Ok(record.response),
+        Some(_) => Err(AppError::IdempotencyKeyConflict),
+        None => apply_transaction(app_state, id, transaction_dto, Some(key)).await,
+    }
+}
+
+async fn apply_transaction(
+    app_state: Arc<AppState>,
+    id: i32,
+    transaction_dto: TransactionDTO,
+    idempotency_key: Option<&str>,
+) -> Result<TransactionResponse, AppError> {
+    let permit = app_state.persistence.reserve()?;
+
+    let transaction = Transaction::new(id, transaction_dto.clone());
+
+    let (client, seq) = app_state.update_client_balance(&transaction).await?;
 
     let client_clone = client.clone();
+    let response = client
+        .response(transaction.currency.as_deref())
+        .with_id(&transaction._id);
 
-    spawn(async move {
-        app_state
-            .db
-            .collection::<Transaction>("transactions")
-            .insert_one(Transaction::new(id, transaction_dto), None)
-            .await?;
+    let record =
+        idempotency_key.map(|key| IdempotencyRecord::new(id, key, &transaction_dto, &response));
 
-        app_state
-            .db
-            .collection::<Client>("clients")
-            .find_one_and_replace(doc! { "_id": client_clone._id }, &client_clone, None)
-            .await?;
+    if let Some(record) = &record {
+        app_state.cache_idempotency_record(record).await;
+    }
 
-        Ok::<(), AppError>(())
-    });
+    permit.send(
+        PendingWrite::new(transaction, Some(client_clone), seq).with_idempotency_record(record),
+    );
 
-    return Ok((StatusCode::OK, Json(client.into())));
+    Ok(response)
 }
 
 pub async fn statement(
     app_state: State<Arc<AppState>>,
     Path(id): Path<i32>,
+    Query(query): Query<StatementQuery>,
 ) -> Result<(StatusCode, Json<StatementDTO>), AppError> {
-    let client = app_state.get_client(id).await?;
+    let statement = app_state.get_statement(id, &query).await?;
+
+    Ok((StatusCode::OK, Json(statement)))
+}
+
+pub async fn history(
+    app_state: State<Arc<AppState>>,
+    Path(id): Path<i32>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<(StatusCode, Json<HistoryDTO>), AppError> {
+    let history = app_state.get_transaction_history(id, &query).await?;
+
+    Ok((StatusCode::OK, Json(history)))
+}
+
+pub async fn create_client(
+    app_state: State<Arc<AppState>>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<ClientDTO>), AppError> {
+    let client_dto = serde_json::from_slice::<ClientDTO>(&body)?;
+
+    let client = app_state.create_client(client_dto.into()).await?;
+
+    Ok((StatusCode::CREATED, Json(client.into())))
+}
+
+pub async fn update_client(
+    app_state: State<Arc<AppState>>,
+    Path(id): Path<i32>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<ClientDTO>), AppError> {
+    let limit_dto = serde_json::from_slice::<LimitDTO>(&body)?;
+
+    let permit = app_state.persistence.reserve()?;
+
+    let (client, seq) = app_state
+        .update_client_limit(id, limit_dto.limit, limit_dto.currency.as_deref())
+        .await?;
+
+    if let Some(seq) = seq {
+        permit.send(PendingWrite::snapshot(client.clone(), seq));
+    }
 
     Ok((StatusCode::OK, Json(client.into())))
 }
+
+pub async fn update_client_status(
+    app_state: State<Arc<AppState>>,
+    Path(id): Path<i32>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<ClientDTO>), AppError> {
+    let status_dto = serde_json::from_slice::<StatusDTO>(&body)?;
+
+    let permit = app_state.persistence.reserve()?;
+
+    let (client, seq) = app_state
+        .update_client_status(id, status_dto.status)
+        .await?;
+
+    if let Some(seq) = seq {
+        permit.send(PendingWrite::snapshot(client.clone(), seq));
+    }
+
+    Ok((StatusCode::OK, Json(client.into())))
+}
+
+pub async fn close_client(
+    app_state: State<Arc<AppState>>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let permit = app_state.persistence.reserve()?;
+
+    let (client, seq) = app_state.close_client(id).await?;
+
+    if let Some(seq) = seq {
+        permit.send(PendingWrite::snapshot(client, seq));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn transfer(
+    app_state: State<Arc<AppState>>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<TransferResponse>), AppError> {
+    let transfer_dto = serde_json::from_slice::<TransferDTO>(&body)?;
+
+    let permit = app_state.persistence.reserve()?;
+
+    let [debit, credit] = app_state.transfer(&transfer_dto).await?;
+
+    let response = TransferResponse {
+        id: debit.transaction.transfer.clone().unwrap_or_default(),
+        from: debit
+            .client
+            .response(debit.transaction.currency.as_deref())
+            .with_id(&debit.transaction._id),
+        to: credit
+            .client
+            .response(credit.transaction.currency.as_deref())
+            .with_id(&credit.transaction._id),
+    };
+
+    if let (Some(debit_seq), Some(credit_seq)) = (debit.seq, credit.seq) {
+        let mut write = PendingWrite::new(debit.transaction, Some(debit.client), debit_seq);
+
+        write.push(credit.transaction, Some(credit.client), credit_seq);
+
+        permit.send(write);
+    }
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+pub async fn reversal(
+    app_state: State<Arc<AppState>>,
+    Path((id, transaction_id)): Path<(i32, String)>,
+) -> Result<(StatusCode, Json<TransactionResponse>), AppError> {
+    let permit = app_state.persistence.reserve()?;
+
+    let (client, reversal, seq) = app_state.reverse_transaction(id, &transaction_id).await?;
+
+    let response = client
+        .response(reversal.currency.as_deref())
+        .with_id(&reversal._id);
+
+    // The reversal row is already stored; only the balance is left.
+    permit.send(PendingWrite::snapshot(client, seq));
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+pub async fn place_hold(
+    app_state: State<Arc<AppState>>,
+    Path(id): Path<i32>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<Hold>), AppError> {
+    let hold_dto = serde_json::from_slice::<HoldDTO>(&body)?;
+
+    let hold = Hold::new(hold_dto, app_state.hold_ttl)?;
+
+    let permit = app_state.persistence.reserve()?;
+
+    let (client, seq) = app_state.place_hold(id, hold.clone()).await?;
+
+    if let Some(seq) = seq {
+        permit.send(PendingWrite::snapshot(client, seq));
+    }
+
+    Ok((StatusCode::CREATED, Json(hold)))
+}
+
+pub async fn capture_hold(
+    app_state: State<Arc<AppState>>,
+    Path((id, hold_id)): Path<(i32, String)>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<TransactionResponse>), AppError> {
+    let capture_dto = match body.is_empty() {
+        true => CaptureDTO::default(),
+        false => serde_json::from_slice::<CaptureDTO>(&body)?,
+    };
+
+    let permit = app_state.persistence.reserve()?;
+
+    let (client, transaction, seq) = app_state
+        .capture_hold(id, &hold_id, capture_dto.value)
+        .await?;
+
+    let response = TransactionResponse::from(client.clone()).with_id(&transaction._id);
+
+    permit.send(PendingWrite::new(transaction, Some(client), seq));
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+pub async fn release_hold(
+    app_state: State<Arc<AppState>>,
+    Path((id, hold_id)): Path<(i32, String)>,
+) -> Result<StatusCode, AppError> {
+    let permit = app_state.persistence.reserve()?;
+
+    let (client, seq) = app_state.release_hold(id, &hold_id).await?;
+
+    if let Some(seq) = seq {
+        permit.send(PendingWrite::snapshot(client, seq));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn persistence_status(
+    app_state: State<Arc<AppState>>,
+) -> Result<(StatusCode, Json<PersistenceStatusDTO>), AppError> {
+    let status = PersistenceStatusDTO {
+        queued: app_state.persistence.len(),
+        failed: app_state.dead_letters.outstanding(),
+    };
+
+    Ok((StatusCode::OK, Json(status)))
+}
+
+pub async fn metrics(
+    app_state: State<Arc<AppState>>,
+) -> Result<(StatusCode, [(header::HeaderName, &'static str); 1], Vec<u8>), AppError> {
+    // Gauges are sampled at scrape time instead of on every queue operation.
+    app_state
+        .metrics
+        .persistence_queued
+        .set(app_state.persistence.len() as i64);
+
+    app_state
+        .metrics
+        .persistence_failed
+        .set(app_state.dead_letters.outstanding() as i64);
+
+    let (content_type, body) = app_state.metrics.render();
+
+    Ok((StatusCode::OK, [(header::CONTENT_TYPE, content_type)], body))
+}
+
+pub async fn live() -> Result<(StatusCode, Json<HealthDTO>), AppError> {
+    Ok((StatusCode::OK, Json(HealthDTO::live())))
+}
+
+pub async fn ready(
+    app_state: State<Arc<AppState>>,
+) -> Result<(StatusCode, Json<HealthDTO>), AppError> {
+    let health = health::readiness(&app_state).await;
+
+    let status = match health.is_ok() {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    Ok((status, Json(health)))
+}
@@ -0,0 +1,128 @@
+use crate::app_state::AppState;
+use mongodb::bson::doc;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    Falha,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ComponentDTO {
+    pub status: HealthStatus,
+
+    #[serde(rename = "detalhe", skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+
+    #[serde(rename = "latencia_ms")]
+    pub latency_ms: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HealthDTO {
+    pub status: HealthStatus,
+
+    #[serde(rename = "componentes", skip_serializing_if = "BTreeMap::is_empty")]
+    pub components: BTreeMap<&'static str, ComponentDTO>,
+}
+
+impl HealthDTO {
+    pub fn live() -> Self {
+        Self {
+            status: HealthStatus::Ok,
+            components: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == HealthStatus::Ok
+    }
+}
+
+/// Runs every readiness check; the instance is ready only if all of them
+/// pass.
+pub async fn readiness(app_state: &AppState) -> HealthDTO {
+    let mut components = BTreeMap::new();
+
+    components.insert("mongodb", check(ping(app_state)).await);
+
+    components.insert("cache", check(probe(app_state.cache.probe())).await);
+
+    components.insert(
+        "semaforo",
+        check(probe(app_state.named_semaphore.probe())).await,
+    );
+
+    components.insert("persistencia", check(backlog(app_state)).await);
+
+    let status = match components
+        .values()
+        .all(|component| component.status == HealthStatus::Ok)
+    {
+        true => HealthStatus::Ok,
+        false => HealthStatus::Falha,
+    };
+
+    HealthDTO { status, components }
+}
+
+async fn check<F>(probe: F) -> ComponentDTO
+where
+    F: std::future::Future<Output = Result<Option<String>, String>>,
+{
+    let start = Instant::now();
+
+    let (status, detail) = match probe.await {
+        Ok(detail) => (HealthStatus::Ok, detail),
+        Err(detail) => (HealthStatus::Falha, Some(detail)),
+    };
+
+    ComponentDTO {
+        status,
+        detail,
+        latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+    }
+}
+
+async fn probe(result: std::io::Result<()>) -> Result<Option<String>, String> {
+    result.map(|_| None).map_err(|err| err.to_string())
+}
+
+async fn ping(app_state: &AppState) -> Result<Option<String>, String> {
+    match tokio::time::timeout(
+        PING_TIMEOUT,
+        app_state.db.run_command(doc! { "ping": 1 }, None),
+    )
+    .await
+    {
+        Ok(Ok(_)) => Ok(None),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err(format!(
+            "Sem resposta ao ping em {}ms",
+            PING_TIMEOUT.as_millis()
+        )),
+    }
+}
+
+async fn backlog(app_state: &AppState) -> Result<Option<String>, String> {
+    let queued = app_state.persistence.len();
+    let failed = app_state.dead_letters.outstanding();
+
+    let detail = format!(
+        "{queued} escritas na fila (limite {}), {failed} com falha",
+        app_state.readiness_max_backlog
+    );
+
+    match queued < app_state.readiness_max_backlog {
+        true => Ok(Some(detail)),
+        false => Err(detail),
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{
+    app_error::AppError,
+    money::Money,
+    transaction::{Kind, Transaction, TransactionDTO},
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use mongodb::bson::{doc, oid::ObjectId, to_bson, Document};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PAGE_SIZE: i64 = 10;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HistoryQuery {
+    #[serde(rename = "tipo")]
+    pub kind: Option<Kind>,
+
+    #[serde(rename = "de")]
+    pub from: Option<String>,
+
+    #[serde(rename = "ate")]
+    pub to: Option<String>,
+
+    #[serde(rename = "valor_min")]
+    pub min_value: Option<Money>,
+
+    #[serde(rename = "valor_max")]
+    pub max_value: Option<Money>,
+
+    #[serde(rename = "limite")]
+    pub limit: Option<i64>,
+
+    pub cursor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryDTO {
+    #[serde(rename(serialize = "transacoes"))]
+    pub transactions: Vec<TransactionDTO>,
+
+    #[serde(rename(serialize = "proximo_cursor"))]
+    pub next_cursor: Option<String>,
+}
+
+impl HistoryQuery {
+    pub fn page_size(&self) -> Result<i64, AppError> {
+        match self.limit {
+            None => Ok(DEFAULT_PAGE_SIZE),
+            Some(limit) if (1..=MAX_PAGE_SIZE).contains(&limit) => Ok(limit),
+            Some(_) => Err(AppError::InvalidQueryParam("limite")),
+        }
+    }
+
+    pub fn filter(&self, id: i32) -> Result<Document, AppError> {
+        let mut filter = doc! { "client": id };
+
+        if let Some(kind) = &self.kind {
+            filter.insert("kind", to_bson(kind)?);
+        }
+
+        let mut date = Document::new();
+
+        if let Some(from) = &self.from {
+            date.insert(
+                "$gte",
+                normalize_date(from).ok_or(AppError::InvalidQueryParam("de"))?,
+            );
+        }
+
+        if let Some(to) = &self.to {
+            date.insert(
+                "$lte",
+                normalize_date(to).ok_or(AppError::InvalidQueryParam("ate"))?,
+            );
+        }
+
+        if !date.is_empty() {
+            filter.insert("date", date);
+        }
+
+        let mut value = Document::new();
+
+        if let Some(min_value) = self.min_value {
+            value.insert("$gte", min_value);
+        }
+
+        if let Some(max_value) = self.max_value {
+            value.insert("$lte", max_value);
+        }
+
+        if !value.is_empty() {
+            filter.insert("value", value);
+        }
+
+        if let Some(cursor) = &self.cursor {
+            let (date, oid) = decode_cursor(cursor).ok_or(AppError::InvalidQueryParam("cursor"))?;
+
+            filter.extend(older_than(&date, Some(oid)));
+        }
+
+        Ok(filter)
+    }
+}
+
+// Everything that sorts after the given entry in the newest-first order.
+// Entries without an id can only be told apart by date.
+pub fn older_than(date: &str, oid: Option<ObjectId>) -> Document {
+    match oid {
+        Some(oid) => doc! {
+            "$or": [
+                { "date": { "$lt": date } },
+                { "date": date, "_id": { "$lt": oid } },
+            ],
+        },
+        None => doc! { "date": { "$lt": date } },
+    }
+}
+
+pub fn encode_cursor(transaction: &Transaction) -> String {
+    format!("{}_{}", transaction.date, transaction._id)
+}
+
+fn decode_cursor(cursor: &str) -> Option<(String, ObjectId)> {
+    let (date, oid) = cursor.split_once('_')?;
+
+    Some((normalize_date(date)?, ObjectId::parse_str(oid).ok()?))
+}
+
+// Dates are stored as fixed-width UTC strings, so they only compare correctly
+// against values rendered in exactly the same format.
+fn normalize_date(date: &str) -> Option<String> {
+    DateTime::parse_from_rfc3339(date).ok().map(|date| {
+        date.with_timezone(&Utc)
+            .to_rfc3339_opts(SecondsFormat::Micros, true)
+    })
+}
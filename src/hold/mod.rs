@@ -0,0 +1,154 @@
+use crate::{
+    app_error::AppError,
+    money::Money,
+    transaction::{deser, new_id, Kind, TransactionDTO},
+};
+use chrono::{Duration, SecondsFormat, Utc};
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Deserializer, Serialize};
+use speedy::{Readable, Writable};
+
+/// Longest a hold may last, in seconds: one year.
+pub const MAX_HOLD_TTL: u64 = 365 * 24 * 60 * 60;
+
+/// Most active holds a client may have at once, which also bounds the
+/// client document kept in the cache.
+pub const MAX_HOLDS: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Readable, Writable)]
+pub struct Hold {
+    pub id: String,
+
+    #[serde(alias = "valor", rename(serialize = "valor"))]
+    pub value: Money,
+
+    #[serde(alias = "descricao", rename(serialize = "descricao"))]
+    pub description: String,
+
+    #[serde(alias = "expira_em", rename(serialize = "expira_em"))]
+    pub expires_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HoldDTO {
+    #[serde(
+        alias = "valor",
+        rename(serialize = "valor"),
+        deserialize_with = "deser::deserialize_value"
+    )]
+    pub value: Money,
+
+    #[serde(
+        alias = "descricao",
+        rename(serialize = "descricao"),
+        deserialize_with = "deser::deserialize_description"
+    )]
+    pub description: String,
+
+    #[serde(
+        alias = "validade",
+        rename(serialize = "validade"),
+        default,
+        deserialize_with = "deserialize_ttl"
+    )]
+    pub ttl: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CaptureDTO {
+    #[serde(alias = "valor", rename(serialize = "valor"), default)]
+    pub value: Option<Money>,
+}
+
+impl Hold {
+    pub fn new(hold_dto: HoldDTO, default_ttl: u64) -> Result<Self, AppError> {
+        let ttl = hold_dto.ttl.unwrap_or(default_ttl);
+
+        let expires_at = i64::try_from(ttl)
+            .ok()
+            .and_then(Duration::try_seconds)
+            .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+            .ok_or(AppError::Overflow)?;
+
+        Ok(Self {
+            id: new_id(),
+            value: hold_dto.value,
+            description: hold_dto.description,
+            expires_at: format_date(expires_at),
+        })
+    }
+
+    pub fn is_active(&self, now: &str) -> bool {
+        self.expires_at.as_str() > now
+    }
+
+    pub fn capture(&self, value: Money) -> TransactionDTO {
+        TransactionDTO {
+            value,
+            kind: Kind::D,
+            description: self.description.clone(),
+            date: now(),
+            id: None,
+            currency: None,
+            transfer: None,
+        }
+    }
+}
+
+fn deserialize_ttl<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    if let Ok(value) = u64::deserialize(deserializer) {
+        if (1..=MAX_HOLD_TTL).contains(&value) {
+            return Ok(Some(value));
+        }
+    }
+
+    Err(serde::de::Error::custom(format!(
+        "Campo 'validade' deve ser um inteiro entre 1 e {MAX_HOLD_TTL} segundos."
+    )))
+}
+
+pub fn now() -> String {
+    format_date(Utc::now())
+}
+
+fn format_date(date: chrono::DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+// Aggregation expression for the funds reserved by the unexpired holds of a
+// client document, optionally leaving one hold out of the sum.
+pub fn held_expr(now: &str, exclude: Option<&str>) -> Document {
+    doc! {
+        "$sum": {
+            "$map": {
+                "input": active_expr(now, exclude),
+                "as": "hold",
+                "in": "$$hold.valor",
+            },
+        },
+    }
+}
+
+/// Number of active holds, for filters that cap them at `MAX_HOLDS`.
+pub fn active_count_expr(now: &str) -> Document {
+    doc! { "$size": active_expr(now, None) }
+}
+
+fn active_expr(now: &str, exclude: Option<&str>) -> Document {
+    let mut cond: Vec<Bson> = vec![doc! { "$gt": ["$$hold.expira_em", now] }.into()];
+
+    if let Some(exclude) = exclude {
+        cond.push(doc! { "$ne": ["$$hold.id", exclude] }.into());
+    }
+
+    doc! {
+        "$filter": {
+            "input": { "$ifNull": ["$holds", []] },
+            "as": "hold",
+            "cond": { "$and": cond },
+        },
+    }
+}
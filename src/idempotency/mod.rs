@@ -0,0 +1,110 @@
+use crate::{
+    app_error::AppError,
+    money::Money,
+    transaction::{Kind, TransactionDTO, TransactionResponse},
+};
+use axum::http::HeaderMap;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use speedy::{Readable, Writable};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Keys share a fixed set of locks and shared memory slots, so the number of
+/// named semaphores and mmap files does not grow with the keys seen.
+pub const STRIPES: u64 = 64;
+
+/// Records kept per stripe until MongoDB has them. The oldest go first; by
+/// then the persistence queue has normally stored them.
+pub const RECORDS_PER_STRIPE: usize = 64;
+
+/// Bytes a stripe may take in the cache, well under what one entry can hold,
+/// so long descriptions drop the oldest records instead of the whole stripe.
+pub const STRIPE_BYTES: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Readable, Writable)]
+pub struct IdempotencyRecord {
+    pub _id: String,
+
+    pub value: Money,
+
+    pub kind: Kind,
+
+    pub description: String,
+
+    pub response: TransactionResponse,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+
+    #[serde(with = "mongodb::bson::serde_helpers::i64_as_bson_datetime")]
+    pub created_at: i64,
+}
+
+impl IdempotencyRecord {
+    pub fn new(
+        id: i32,
+        key: &str,
+        transaction: &TransactionDTO,
+        response: &TransactionResponse,
+    ) -> Self {
+        Self {
+            _id: Self::record_id(id, key),
+            value: transaction.value,
+            kind: transaction.kind.clone(),
+            description: transaction.description.clone(),
+            response: response.clone(),
+            currency: transaction.currency.clone(),
+            created_at: Utc::now().timestamp_millis(),
+        }
+    }
+
+    pub fn record_id(id: i32, key: &str) -> String {
+        format!("{id}-{key}")
+    }
+
+    /// Name of the lock and cache slot the record belongs to. The hasher is
+    /// built without random keys, so every instance agrees on it.
+    pub fn stripe(record_id: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+
+        record_id.hash(&mut hasher);
+
+        format!("idempotency-{}", hasher.finish() % STRIPES)
+    }
+
+    pub fn matches(&self, transaction: &TransactionDTO) -> bool {
+        self.value == transaction.value
+            && self.kind == transaction.kind
+            && self.description == transaction.description
+            && self.currency == transaction.currency
+    }
+
+    pub fn is_expired(&self, ttl: u64) -> bool {
+        Utc::now().timestamp_millis() - self.created_at > (ttl * 1000) as i64
+    }
+}
+
+pub fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, AppError> {
+    let Some(value) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+
+    // The key ends up in the record id, so it is kept short and to a charset
+    // that needs no escaping anywhere.
+    match value.to_str() {
+        Ok(key)
+            if (1..=64).contains(&key.len())
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+        {
+            Ok(Some(key.to_string()))
+        }
+        _ => Err(AppError::InvalidIdempotencyKey),
+    }
+}
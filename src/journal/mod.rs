@@ -0,0 +1,334 @@
+use crate::{app_error::AppError, client::Client, persistence, transaction::Transaction};
+use mongodb::bson::doc;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record {
+    Append {
+        seq: u64,
+        transaction: Transaction,
+        client: Option<Box<Client>>,
+    },
+    Snapshot {
+        seq: u64,
+        client: Box<Client>,
+    },
+    Transfer {
+        seq: u64,
+        debit: Transaction,
+        from: Box<Client>,
+        credit: Transaction,
+        to: Box<Client>,
+    },
+    Commit {
+        seq: u64,
+    },
+}
+
+/// The file is rewritten with only the pending entries once it grows past
+/// this many bytes, so it stays bounded when it is never fully committed.
+const COMPACT_AFTER: u64 = 64 * 1024 * 1024;
+
+struct JournalFile {
+    file: File,
+    len: u64,
+    // Set when a failed append could not be cut back off.
+    torn: bool,
+    next_seq: u64,
+    // Lines of the entries not committed yet, kept for compaction.
+    pending: HashMap<u64, Vec<u8>>,
+}
+
+/// Append-only log of accepted transactions. Every entry is fsync'd before the
+/// response goes out and marked as committed once MongoDB has the write, so
+/// anything left uncommitted after a crash is replayed on the next startup.
+///
+/// Replay is at-least-once: a crash between the MongoDB write and the commit
+/// record replays an entry that was already stored. That is harmless because
+/// ledger rows keep their ids and only missing ones are inserted, and client
+/// snapshots are full documents replaced by the newest one journaled.
+///
+/// A failed append is truncated away before anything else is written, so
+/// a torn line can only be left by a crash and is always the last one.
+pub struct Journal {
+    path: String,
+    inner: Mutex<JournalFile>,
+}
+
+impl Journal {
+    pub async fn open(path: &str) -> Result<Self, AppError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        let len = file.metadata().await?.len();
+
+        Ok(Self {
+            path: path.to_string(),
+            inner: Mutex::new(JournalFile {
+                file,
+                len,
+                torn: false,
+                next_seq: 0,
+                pending: HashMap::new(),
+            }),
+        })
+    }
+
+    pub async fn append(
+        &self,
+        transaction: &Transaction,
+        client: Option<&Client>,
+    ) -> Result<u64, AppError> {
+        self.push(|seq| Record::Append {
+            seq,
+            transaction: transaction.clone(),
+            client: client.cloned().map(Box::new),
+        })
+        .await
+    }
+
+    /// Journals both legs of a transfer as one entry, so neither can be left
+    /// pending without the other.
+    pub async fn append_transfer(
+        &self,
+        debit: &Transaction,
+        from: &Client,
+        credit: &Transaction,
+        to: &Client,
+    ) -> Result<u64, AppError> {
+        self.push(|seq| Record::Transfer {
+            seq,
+            debit: debit.clone(),
+            from: Box::new(from.clone()),
+            credit: credit.clone(),
+            to: Box::new(to.clone()),
+        })
+        .await
+    }
+
+    /// Journals a client change that is not a transaction, like a new limit.
+    pub async fn snapshot(&self, client: &Client) -> Result<u64, AppError> {
+        self.push(|seq| Record::Snapshot {
+            seq,
+            client: Box::new(client.clone()),
+        })
+        .await
+    }
+
+    async fn push(&self, record: impl FnOnce(u64) -> Record) -> Result<u64, AppError> {
+        let mut guard = self.inner.lock().await;
+
+        let seq = guard.next_seq;
+        let line = Self::line(&record(seq))?;
+
+        Self::write_line(&mut guard, &line).await?;
+
+        guard.next_seq += 1;
+        guard.pending.insert(seq, line);
+
+        Ok(seq)
+    }
+
+    /// Entries not yet marked as stored in MongoDB.
+    pub async fn pending(&self) -> usize {
+        self.inner.lock().await.pending.len()
+    }
+
+    /// Marks entries as stored in MongoDB, with a single fsync for all of them.
+    pub async fn commit(&self, seqs: &[u64]) -> Result<(), AppError> {
+        let mut guard = self.inner.lock().await;
+
+        let committed: Vec<u64> = seqs
+            .iter()
+            .copied()
+            .filter(|seq| guard.pending.remove(seq).is_some())
+            .collect();
+
+        if committed.is_empty() {
+            return Ok(());
+        }
+
+        if guard.pending.is_empty() {
+            guard.file.set_len(0).await?;
+            guard.file.sync_data().await?;
+
+            guard.len = 0;
+            guard.torn = false;
+
+            return Ok(());
+        }
+
+        if guard.len >= COMPACT_AFTER {
+            return self.compact(&mut guard).await;
+        }
+
+        let mut lines = Vec::new();
+
+        for seq in committed {
+            lines.extend(Self::line(&Record::Commit { seq })?);
+        }
+
+        Self::write_line(&mut guard, &lines).await
+    }
+
+    // Rewrites the file with only the pending entries, in the order they were
+    // journaled. The new file is synced before it takes the old one's place,
+    // so a crash leaves one or the other.
+    async fn compact(&self, journal: &mut JournalFile) -> Result<(), AppError> {
+        let mut seqs: Vec<u64> = journal.pending.keys().copied().collect();
+
+        seqs.sort_unstable();
+
+        let mut contents = Vec::new();
+
+        for seq in seqs {
+            contents.extend_from_slice(&journal.pending[&seq]);
+        }
+
+        let compacted = format!("{}.compact", self.path);
+
+        let mut file = File::create(&compacted).await?;
+
+        file.write_all(&contents).await?;
+        file.sync_all().await?;
+
+        tokio::fs::rename(&compacted, &self.path).await?;
+
+        let dir = match Path::new(&self.path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        File::open(dir).await?.sync_all().await?;
+
+        journal.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        journal.len = contents.len() as u64;
+        journal.torn = false;
+
+        Ok(())
+    }
+
+    pub async fn replay(&self, db: &mongodb::Database) -> Result<(), AppError> {
+        let mut guard = self.inner.lock().await;
+
+        let mut lines = BufReader::new(File::open(&self.path).await?).lines();
+
+        let mut appended = Vec::new();
+        let mut committed = HashSet::new();
+
+        while let Some(line) = lines.next_line().await? {
+            // A torn write from a crash can only ever be the last line, and it
+            // was never acknowledged, so it is safe to skip.
+            match serde_json::from_str::<Record>(&line) {
+                Ok(Record::Append {
+                    seq,
+                    transaction,
+                    client,
+                }) => appended.push((seq, Some(transaction), client)),
+                Ok(Record::Snapshot { seq, client }) => appended.push((seq, None, Some(client))),
+                Ok(Record::Transfer {
+                    seq,
+                    debit,
+                    from,
+                    credit,
+                    to,
+                }) => {
+                    appended.push((seq, Some(debit), Some(from)));
+                    appended.push((seq, Some(credit), Some(to)));
+                }
+                Ok(Record::Commit { seq }) => {
+                    committed.insert(seq);
+                }
+                Err(_) => continue,
+            }
+        }
+
+        let mut transactions = Vec::new();
+        let mut dirty_clients = HashSet::new();
+        let mut latest_clients = HashMap::new();
+
+        for (seq, transaction, client) in appended {
+            if !committed.contains(&seq) {
+                transactions.extend(transaction);
+            }
+
+            // Entries written in atomic mode carry no snapshot because MongoDB
+            // already holds the balance; only the ledger row needs replaying.
+            if let Some(client) = client {
+                if !committed.contains(&seq) {
+                    dirty_clients.insert(client._id);
+                }
+
+                latest_clients.insert(client._id, *client);
+            }
+        }
+
+        persistence::insert_new(&db.collection::<Transaction>("transactions"), &transactions)
+            .await?;
+
+        for id in dirty_clients {
+            let client = &latest_clients[&id];
+
+            db.collection::<Client>("clients")
+                .find_one_and_replace(doc! { "_id": id }, client, None)
+                .await?;
+        }
+
+        guard.file.set_len(0).await?;
+        guard.len = 0;
+
+        Ok(())
+    }
+
+    fn line(record: &Record) -> Result<Vec<u8>, AppError> {
+        let mut line = serde_json::to_vec(record)?;
+
+        line.push(b'\n');
+
+        Ok(line)
+    }
+
+    // Writes and syncs whole lines. Whatever a failed attempt left behind is
+    // cut back off, so the next line is not appended onto a torn one and
+    // dropped with it on replay.
+    async fn write_line(journal: &mut JournalFile, line: &[u8]) -> Result<(), AppError> {
+        if journal.torn {
+            journal.file.set_len(journal.len).await?;
+            journal.torn = false;
+        }
+
+        let written = async {
+            journal.file.write_all(line).await?;
+            journal.file.sync_data().await
+        }
+        .await;
+
+        if let Err(err) = written {
+            journal.torn = journal.file.set_len(journal.len).await.is_err();
+
+            return Err(err.into());
+        }
+
+        journal.len += line.len() as u64;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+use hyper::server::accept::Accept;
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Semaphore},
+};
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+const HANDSHAKE_BACKLOG: usize = 1024;
+
+/// Handshakes in progress at once; past it new connections wait in the
+/// kernel's accept queue.
+const MAX_HANDSHAKES: usize = 256;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Accept errors like EMFILE repeat until something changes, so the loop
+// backs off instead of spinning on them.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Connections that already went through the TLS handshake, ready to be
+/// handed to `axum::Server::builder`.
+pub struct TlsIncoming {
+    connections: mpsc::Receiver<TlsStream<TcpStream>>,
+}
+
+impl Accept for TlsIncoming {
+    type Conn = TlsStream<TcpStream>;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.connections.poll_recv(cx).map(|conn| conn.map(Ok))
+    }
+}
+
+pub fn tls_acceptor(cert_path: &str, key_path: &str) -> io::Result<TlsAcceptor> {
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+pub async fn bind_tls(addr: SocketAddr, acceptor: TlsAcceptor) -> io::Result<TlsIncoming> {
+    let listener = TcpListener::bind(addr).await?;
+
+    let (sender, connections) = mpsc::channel(HANDSHAKE_BACKLOG);
+
+    let handshakes = Arc::new(Semaphore::new(MAX_HANDSHAKES));
+
+    tokio::spawn(async move {
+        let mut backoff = ACCEPT_BACKOFF_MIN;
+
+        while !sender.is_closed() {
+            let Ok(permit) = handshakes.clone().acquire_owned().await else {
+                break;
+            };
+
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    tracing::warn!(error = %err, %addr, "could not accept connection");
+
+                    tokio::time::sleep(backoff).await;
+
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+
+                    continue;
+                }
+            };
+
+            backoff = ACCEPT_BACKOFF_MIN;
+
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+
+            // A slow or broken handshake must not hold up the next client.
+            tokio::spawn(async move {
+                let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+
+                let stream = match handshake.await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        tracing::debug!(error = %err, %peer, "TLS handshake failed");
+
+                        return;
+                    }
+                    Err(_) => {
+                        tracing::debug!(%peer, "TLS handshake timed out");
+
+                        return;
+                    }
+                };
+
+                drop(permit);
+
+                let _ = sender.send(stream).await;
+            });
+        }
+    });
+
+    Ok(TlsIncoming { connections })
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let certs = rustls_pemfile::certs(&mut reader)?;
+
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificate found in {path}"),
+        ));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no private key found in {path}"),
+                ))
+            }
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use crate::transaction::new_id;
+use axum::{
+    extract::{MatchedPath, Path},
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use mongodb::event::command::{
+    CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+};
+use opentelemetry::global;
+use opentelemetry_http::HeaderExtractor;
+use opentelemetry_sdk::trace::Tracer;
+use std::{
+    backtrace::Backtrace,
+    collections::HashMap,
+    panic::PanicHookInfo,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{field, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Commands that never report back, for example because their connection
+/// was dropped, would keep their spans forever, so the map is bounded.
+const MAX_OPEN_COMMANDS: usize = 4096;
+
+/// Open spans older than this are dropped once the map is full.
+const STALE_COMMAND: Duration = Duration::from_secs(300);
+
+/// JSON lines on stderr, one object per event with the fields of every span
+/// it happened in. `filter` takes the same directives as `RUST_LOG` and also
+/// decides which spans reach `tracer`, when there is one.
+pub fn init(filter: &str, tracer: Option<Tracer>) {
+    tracing_subscriber::registry()
+        .with(EnvFilter::new(filter))
+        .with(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .with_writer(std::io::stderr),
+        )
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .init();
+
+    std::panic::set_hook(Box::new(log_panic));
+}
+
+fn log_panic(info: &PanicHookInfo) {
+    let message = match info.payload().downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match info.payload().downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => String::from("Box<dyn Any>"),
+        },
+    };
+
+    tracing::error!(
+        panic = %message,
+        location = info.location().map(|location| location.to_string()),
+        thread = std::thread::current().name(),
+        backtrace = %Backtrace::capture(),
+        "panicked"
+    );
+}
+
+/// Opens the span every event of a request is logged under. The request id
+/// is taken from the `X-Request-Id` header, or made up when there is none,
+/// and sent back on the response. A `traceparent` header makes the span a
+/// child of the caller's trace.
+pub async fn trace_request<B>(
+    route: Option<MatchedPath>,
+    params: Option<Path<HashMap<String, String>>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let start = Instant::now();
+
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 64)
+        .map_or_else(new_id, str::to_string);
+
+    let route = route.as_ref().map(|route| route.as_str());
+
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        route,
+        client_id = field::Empty,
+        request_id = %request_id,
+        otel.name = format!("{} {}", request.method(), route.unwrap_or("unmatched")),
+        otel.kind = "server",
+        otel.status_code = field::Empty,
+        http.status_code = field::Empty,
+    );
+
+    span.set_parent(global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    }));
+
+    if let Some(id) = params.as_ref().and_then(|Path(params)| params.get("id")) {
+        span.record("client_id", id.as_str());
+    }
+
+    let mut response = next.run(request).instrument(span.clone()).await;
+
+    let status = response.status();
+    let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    span.record("http.status_code", status.as_u16());
+
+    if status.is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+
+    span.in_scope(|| {
+        if status.is_server_error() {
+            tracing::error!(status = status.as_u16(), elapsed_ms, "request failed");
+        } else {
+            tracing::info!(status = status.as_u16(), elapsed_ms, "request completed");
+        }
+    });
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    response
+}
+
+/// Gives every MongoDB command a span under whatever was running when it was
+/// sent, then hands the events on to `next`. The spans follow the
+/// OpenTelemetry database conventions so they show up as client calls.
+pub struct MongoCommands {
+    next: Option<Arc<dyn CommandEventHandler>>,
+    spans: Mutex<HashMap<i32, (Instant, Span)>>,
+}
+
+impl MongoCommands {
+    pub fn new(next: Option<Arc<dyn CommandEventHandler>>) -> Self {
+        Self {
+            next,
+            spans: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn take_span(&self, request_id: i32) -> Span {
+        self.spans
+            .lock()
+            .unwrap()
+            .remove(&request_id)
+            .map_or_else(Span::none, |(_, span)| span)
+    }
+
+    // Sweeps stale spans when the map is full; a command started while it
+    // stays full goes without a span.
+    fn insert_span(&self, request_id: i32, span: Span) {
+        let mut spans = self.spans.lock().unwrap();
+
+        if spans.len() >= MAX_OPEN_COMMANDS {
+            spans.retain(|_, (started_at, _)| started_at.elapsed() < STALE_COMMAND);
+        }
+
+        if spans.len() < MAX_OPEN_COMMANDS {
+            spans.insert(request_id, (Instant::now(), span));
+        }
+    }
+}
+
+impl CommandEventHandler for MongoCommands {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        let span = tracing::info_span!(
+            "mongodb",
+            command = %event.command_name,
+            db = %event.db,
+            command_id = event.request_id,
+            otel.name = %event.command_name,
+            otel.kind = "client",
+            otel.status_code = field::Empty,
+            db.system = "mongodb",
+            db.name = %event.db,
+            db.operation = %event.command_name,
+        );
+
+        self.insert_span(event.request_id, span);
+
+        if let Some(next) = &self.next {
+            next.handle_command_started_event(event);
+        }
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.take_span(event.request_id).in_scope(|| {
+            tracing::debug!(
+                elapsed_ms = event.duration.as_secs_f64() * 1000.0,
+                "command succeeded"
+            );
+        });
+
+        if let Some(next) = &self.next {
+            next.handle_command_succeeded_event(event);
+        }
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        let span = self.take_span(event.request_id);
+
+        span.record("otel.status_code", "ERROR");
+
+        span.in_scope(|| {
+            tracing::warn!(
+                error = %event.failure,
+                elapsed_ms = event.duration.as_secs_f64() * 1000.0,
+                "command failed"
+            );
+        });
+
+        if let Some(next) = &self.next {
+            next.handle_command_failed_event(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use opentelemetry::trace::{SpanId, TraceId, TracerProvider as _};
+    use opentelemetry_sdk::{
+        propagation::TraceContextPropagator, testing::trace::InMemorySpanExporter,
+        trace::TracerProvider,
+    };
+    use tower::ServiceExt;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn traceparent_makes_the_request_span_a_child() {
+        let exporter = InMemorySpanExporter::default();
+
+        // Flushed by hand below: shutting the provider down, as
+        // `telemetry::shutdown` does, would clear the in-memory exporter.
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+
+        let tracer = provider.tracer("rinha-test");
+
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let app = Router::new()
+            .route("/ping", get(|| async { "pong" }))
+            .route_layer(middleware::from_fn(trace_request));
+
+        let request = Request::builder()
+            .uri("/ping")
+            .header("traceparent", format!("00-{TRACE_ID}-{PARENT_ID}-01"))
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert!(response.status().is_success());
+
+        for result in provider.force_flush() {
+            result.unwrap();
+        }
+
+        let spans = exporter.get_finished_spans().unwrap();
+
+        let span = spans
+            .iter()
+            .find(|span| span.name == "GET /ping")
+            .expect("request span was not exported");
+
+        assert_eq!(
+            span.span_context.trace_id(),
+            TraceId::from_hex(TRACE_ID).unwrap()
+        );
+        assert_eq!(span.parent_span_id, SpanId::from_hex(PARENT_ID).unwrap());
+    }
+}
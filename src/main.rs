@@ -3,58 +3,315 @@ mod app_error;
 mod app_state;
 mod balance;
 mod client;
+mod currency;
+mod dead_letter;
 mod handlers;
+mod health;
+mod history;
+mod hold;
+mod idempotency;
+mod journal;
+mod listener;
+mod logging;
+mod metrics;
+mod money;
+mod persistence;
+mod rate_limit;
+mod reconcile;
 mod statement;
+mod telemetry;
 mod transaction;
+mod transfer;
 mod utils;
 
 use app_config::config;
 use app_state::AppState;
 use axum::{
-    routing::{get, post},
+    middleware,
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use dotenv::dotenv;
 use hyperlocal::UnixServerExt;
 use std::os::unix::fs::PermissionsExt;
+use std::process::ExitCode;
+use std::time::Duration;
 use std::{fs::remove_file, path};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     dotenv().ok();
 
-    if let Ok(dir_entries) = std::fs::read_dir("/dev/shm") {
-        for dir_entry in dir_entries {
-            if let Ok(dir_entry) = dir_entry {
-                if dir_entry
-                    .file_name()
-                    .to_str()
-                    .is_some_and(|file_name| file_name.starts_with("dk-rinha-2024"))
-                {
-                    let _ = remove_file(dir_entry.path());
-                }
-            }
+    let config = config().unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(1);
+    });
+
+    let tracer = telemetry::tracer(&config).unwrap_or_else(|err| {
+        eprintln!("Could not set up trace export: {err}");
+        std::process::exit(1);
+    });
+
+    logging::init(&config.log_level, tracer);
+
+    match std::env::args().nth(1).as_deref() {
+        None | Some("serve") => {}
+        Some("reconcile") => {
+            let code = reconcile_once(&config).await;
+
+            telemetry::shutdown().await;
+
+            return code;
+        }
+        Some(command) => {
+            tracing::error!(command, "unknown command, expected 'serve' or 'reconcile'");
+
+            return ExitCode::from(2);
         }
     }
 
-    let config = config();
+    clear_shared_memory(&config.shm_prefix);
+
+    // Certificates are loaded up front so a bad path fails before anything
+    // else is started.
+    let tls_acceptor = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) if !config.tls_listen_addrs.is_empty() => Some(
+            listener::tls_acceptor(cert_path, key_path).unwrap_or_else(|err| {
+                tracing::error!(
+                    error = %err,
+                    cert_path,
+                    key_path,
+                    "could not load TLS certificate and key"
+                );
+                std::process::exit(1);
+            }),
+        ),
+        _ => None,
+    };
 
     let app_state = AppState::new(&config).await;
 
     let app = Router::new()
+        .route("/clientes", post(handlers::create_client))
+        .route(
+            "/clientes/:id",
+            patch(handlers::update_client).delete(handlers::close_client),
+        )
         .route("/clientes/:id/extrato", get(handlers::statement))
-        .route("/clientes/:id/transacoes", post(handlers::transaction))
-        .with_state(app_state);
+        .route(
+            "/clientes/:id/transacoes",
+            get(handlers::history).post(handlers::transaction),
+        )
+        .route(
+            "/clientes/:id/situacao",
+            put(handlers::update_client_status),
+        )
+        .route(
+            "/clientes/:id/transacoes/:tx_id/estorno",
+            post(handlers::reversal),
+        )
+        .route("/clientes/:id/reservas", post(handlers::place_hold))
+        .route(
+            "/clientes/:id/reservas/:hold_id",
+            delete(handlers::release_hold),
+        )
+        .route(
+            "/clientes/:id/reservas/:hold_id/captura",
+            post(handlers::capture_hold),
+        )
+        .route("/transferencias", post(handlers::transfer))
+        .route("/persistencia", get(handlers::persistence_status))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            rate_limit::enforce,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            metrics::track,
+        ))
+        .route_layer(middleware::from_fn(logging::trace_request))
+        // Probes are left out of the request logs and metrics.
+        .route("/health/live", get(handlers::live))
+        .route("/health/ready", get(handlers::ready));
+
+    // Scrapes stay off the API listeners when a separate one is configured.
+    let (app, metrics_app) = match config.metrics_listen_addr {
+        Some(_) => (
+            app.with_state(app_state.clone()),
+            Some(
+                Router::new()
+                    .route("/metrics", get(handlers::metrics))
+                    .with_state(app_state.clone()),
+            ),
+        ),
+        None => (
+            app.route("/metrics", get(handlers::metrics))
+                .with_state(app_state.clone()),
+            None,
+        ),
+    };
+
+    let (shutdown, shutdown_requested) = watch::channel(());
+
+    let graceful = || {
+        let mut shutdown_requested = shutdown_requested.clone();
+
+        async move {
+            let _ = shutdown_requested.changed().await;
+        }
+    };
+
+    let mut servers = JoinSet::new();
+
+    if let Some(socket_path) = &config.socket_path {
+        let path = path::Path::new(socket_path);
+
+        if path.exists() {
+            remove_file(path).expect("Could not remove old socket!");
+        }
+
+        let builder = axum::Server::bind_unix(path).unwrap();
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(config.socket_mode))
+            .unwrap();
+
+        servers.spawn(
+            builder
+                .serve(app.clone().into_make_service())
+                .with_graceful_shutdown(graceful()),
+        );
+    }
+
+    for addr in &config.listen_addrs {
+        let builder = axum::Server::try_bind(addr)
+            .unwrap_or_else(|err| panic!("Could not bind to {addr}: {err}"));
+
+        servers.spawn(
+            builder
+                .serve(app.clone().into_make_service())
+                .with_graceful_shutdown(graceful()),
+        );
+    }
+
+    if let Some(tls_acceptor) = tls_acceptor {
+        for addr in &config.tls_listen_addrs {
+            let incoming = listener::bind_tls(*addr, tls_acceptor.clone())
+                .await
+                .unwrap_or_else(|err| panic!("Could not bind to {addr}: {err}"));
+
+            servers.spawn(
+                axum::Server::builder(incoming)
+                    .serve(app.clone().into_make_service())
+                    .with_graceful_shutdown(graceful()),
+            );
+        }
+    }
+
+    if let (Some(addr), Some(metrics_app)) = (&config.metrics_listen_addr, metrics_app) {
+        let builder = axum::Server::try_bind(addr)
+            .unwrap_or_else(|err| panic!("Could not bind to {addr}: {err}"));
 
-    let path = path::Path::new(config.socket_path.as_str());
+        servers.spawn(
+            builder
+                .serve(metrics_app.into_make_service())
+                .with_graceful_shutdown(graceful()),
+        );
+    }
+
+    tokio::spawn(async move {
+        shutdown_signal().await;
+
+        let _ = shutdown.send(());
+    });
+
+    // Servers only return once they stopped accepting and every in-flight
+    // request got its response.
+    while let Some(result) = servers.join_next().await {
+        result.unwrap().unwrap();
+    }
+
+    let pending = app_state
+        .drain(Duration::from_secs(config.shutdown_timeout))
+        .await;
+
+    let pending = pending + app_state.dead_letters.outstanding();
+
+    if pending > 0 {
+        tracing::warn!(pending, "pending writes were left to the journal replay");
+    }
 
-    if path.exists() {
-        remove_file(path).expect("Could not remove old socket!");
+    if let Some(socket_path) = &config.socket_path {
+        let _ = remove_file(socket_path);
     }
 
-    let builder = axum::Server::bind_unix(path).unwrap();
+    clear_shared_memory(&config.shm_prefix);
+
+    telemetry::shutdown().await;
+
+    ExitCode::SUCCESS
+}
+
+// Only talks to MongoDB, so it can run next to live instances without touching
+// their shared memory, journal or dead letters. Exits with 1 on drift.
+async fn reconcile_once(config: &app_config::Config) -> ExitCode {
+    let db = AppState::connect(config, None)
+        .await
+        .default_database()
+        .unwrap();
+
+    let report = match reconcile::run(
+        &db,
+        reconcile::window(config.persistence_mode, config.latest_transactions_len),
+        None,
+        false,
+    )
+    .await
+    {
+        Ok(report) => report,
+        Err(err) => {
+            tracing::error!(error = %err, details = ?err, "reconciliation failed");
+
+            return ExitCode::FAILURE;
+        }
+    };
 
-    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o777)).unwrap();
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
 
-    builder.serve(app.into_make_service()).await.unwrap();
+    if let Some(path) = &config.reconcile_report_path {
+        if let Err(err) = report.write(path).await {
+            tracing::error!(error = %err, path, "could not write reconciliation report");
+        }
+    }
+
+    if report.discrepancies.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM!");
+
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
+}
+
+fn clear_shared_memory(prefix: &str) {
+    let semaphore_prefix = format!("sem.{prefix}");
+
+    if let Ok(dir_entries) = std::fs::read_dir("/dev/shm") {
+        for dir_entry in dir_entries.flatten() {
+            if dir_entry.file_name().to_str().is_some_and(|file_name| {
+                file_name.starts_with(prefix) || file_name.starts_with(&semaphore_prefix)
+            }) {
+                let _ = remove_file(dir_entry.path());
+            }
+        }
+    }
 }
@@ -0,0 +1,195 @@
+use crate::{app_error::ErrorVariant, app_state::AppState};
+use axum::{
+    extract::{MatchedPath, State},
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use mongodb::event::command::{CommandEventHandler, CommandFailedEvent, CommandSucceededEvent};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::{sync::Arc, time::Instant};
+
+const NAMESPACE: &str = "rinha";
+
+/// Every series exposed on `/metrics`. The collectors are cheap to clone and
+/// share their values, so parts of them are handed to the semaphore and the
+/// MongoDB client.
+pub struct Metrics {
+    registry: Registry,
+    pub requests: IntCounterVec,
+    pub request_duration: HistogramVec,
+    pub cache_lookups: IntCounterVec,
+    pub semaphore_wait: Histogram,
+    pub mongodb_commands: HistogramVec,
+    pub persistence_queued: IntGauge,
+    pub persistence_failed: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Requests handled").namespace(NAMESPACE),
+            &["method", "route", "status", "outcome"],
+        )
+        .unwrap();
+
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time to produce a response",
+            )
+            .namespace(NAMESPACE),
+            &["method", "route", "outcome"],
+        )
+        .unwrap();
+
+        let cache_lookups = IntCounterVec::new(
+            Opts::new("cache_lookups_total", "Client lookups in shared memory")
+                .namespace(NAMESPACE),
+            &["result"],
+        )
+        .unwrap();
+
+        let semaphore_wait = Histogram::with_opts(
+            HistogramOpts::new(
+                "semaphore_wait_seconds",
+                "Time spent waiting for a client lock",
+            )
+            .namespace(NAMESPACE)
+            .buckets(vec![
+                0.00001, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0,
+            ]),
+        )
+        .unwrap();
+
+        let mongodb_commands = HistogramVec::new(
+            HistogramOpts::new("mongodb_command_duration_seconds", "MongoDB round trips")
+                .namespace(NAMESPACE),
+            &["command", "outcome"],
+        )
+        .unwrap();
+
+        let persistence_queued = IntGauge::with_opts(
+            Opts::new(
+                "persistence_queued",
+                "Writes waiting for MongoDB, including the batch being written",
+            )
+            .namespace(NAMESPACE),
+        )
+        .unwrap();
+
+        let persistence_failed = IntGauge::with_opts(
+            Opts::new(
+                "persistence_failed",
+                "Writes in the dead letter file waiting for a retry",
+            )
+            .namespace(NAMESPACE),
+        )
+        .unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(cache_lookups.clone())).unwrap();
+        registry.register(Box::new(semaphore_wait.clone())).unwrap();
+        registry
+            .register(Box::new(mongodb_commands.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(persistence_queued.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(persistence_failed.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            requests,
+            request_duration,
+            cache_lookups,
+            semaphore_wait,
+            mongodb_commands,
+            persistence_queued,
+            persistence_failed,
+        }
+    }
+
+    pub fn render(&self) -> (&'static str, Vec<u8>) {
+        let encoder = TextEncoder::new();
+        let mut buffer = Vec::new();
+
+        encoder
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+
+        (prometheus::TEXT_FORMAT, buffer)
+    }
+
+    pub fn mongodb_handler(&self) -> Arc<dyn CommandEventHandler> {
+        Arc::new(MongoCommands(self.mongodb_commands.clone()))
+    }
+}
+
+struct MongoCommands(HistogramVec);
+
+impl CommandEventHandler for MongoCommands {
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.0
+            .with_label_values(&[&event.command_name, "ok"])
+            .observe(event.duration.as_secs_f64());
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        self.0
+            .with_label_values(&[&event.command_name, "error"])
+            .observe(event.duration.as_secs_f64());
+    }
+}
+
+/// Counts and times every routed request. Errors are labelled with the
+/// `AppError` variant that produced them, anything else axum rejected before
+/// reaching a handler is a `rejection`.
+pub async fn track<B>(
+    State(app_state): State<Arc<AppState>>,
+    route: Option<MatchedPath>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let start = Instant::now();
+
+    let method = request.method().clone();
+    let route = route
+        .as_ref()
+        .map_or("unmatched", |route| route.as_str())
+        .to_string();
+
+    let response = next.run(request).await;
+
+    let status = response.status();
+
+    let outcome = match response.extensions().get::<ErrorVariant>() {
+        Some(ErrorVariant(variant)) => variant,
+        None if status.is_client_error() || status.is_server_error() => "rejection",
+        None => "ok",
+    };
+
+    app_state
+        .metrics
+        .requests
+        .with_label_values(&[method.as_str(), &route, status.as_str(), outcome])
+        .inc();
+
+    app_state
+        .metrics
+        .request_duration
+        .with_label_values(&[method.as_str(), &route, outcome])
+        .observe(start.elapsed().as_secs_f64());
+
+    response
+}
@@ -0,0 +1,130 @@
+use crate::app_error::AppError;
+use mongodb::bson::Bson;
+use serde::{Deserialize, Serialize};
+use speedy::{Readable, Writable};
+
+/// Amount in minor units. Arithmetic only goes through the checked helpers,
+/// so an overflow surfaces as `AppError::Overflow` instead of wrapping.
+#[derive(
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Readable,
+    Writable,
+)]
+#[serde(transparent)]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Self = Self(0);
+
+    pub const MAX: Self = Self(i64::MAX);
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+
+    pub fn checked_add(self, other: Self) -> Result<Self, AppError> {
+        self.0
+            .checked_add(other.0)
+            .map(Self)
+            .ok_or(AppError::Overflow)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Result<Self, AppError> {
+        self.0
+            .checked_sub(other.0)
+            .map(Self)
+            .ok_or(AppError::Overflow)
+    }
+
+    pub fn checked_neg(self) -> Result<Self, AppError> {
+        self.0.checked_neg().map(Self).ok_or(AppError::Overflow)
+    }
+
+    pub fn checked_sum(values: impl IntoIterator<Item = Self>) -> Result<Self, AppError> {
+        values
+            .into_iter()
+            .try_fold(Self::ZERO, |total, value| total.checked_add(value))
+    }
+}
+
+// Stored as a 64-bit integer; documents written with 32-bit values still
+// deserialize since serde widens them.
+impl From<Money> for Bson {
+    fn from(money: Money) -> Self {
+        Bson::Int64(money.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_add_at_the_limits() {
+        assert_eq!(Money::MAX.checked_add(Money::ZERO).unwrap(), Money::MAX);
+        assert_eq!(
+            Money(i64::MAX - 1).checked_add(Money(1)).unwrap(),
+            Money::MAX
+        );
+
+        assert!(matches!(
+            Money::MAX.checked_add(Money(1)),
+            Err(AppError::Overflow)
+        ));
+        assert!(matches!(
+            Money(i64::MIN).checked_add(Money(-1)),
+            Err(AppError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn checked_neg_at_the_limits() {
+        assert_eq!(Money::MAX.checked_neg().unwrap(), Money(-i64::MAX));
+        assert_eq!(Money(-i64::MAX).checked_neg().unwrap(), Money::MAX);
+
+        assert!(matches!(
+            Money(i64::MIN).checked_neg(),
+            Err(AppError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn checked_sum_overflows() {
+        assert!(matches!(
+            Money::checked_sum([Money::MAX, Money(1)]),
+            Err(AppError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        for money in [Money::ZERO, Money(-1), Money::MAX, Money(i64::MIN)] {
+            let json = serde_json::to_string(&money).unwrap();
+
+            assert_eq!(json, money.0.to_string());
+            assert_eq!(serde_json::from_str::<Money>(&json).unwrap(), money);
+        }
+    }
+
+    #[test]
+    fn bson_round_trip() {
+        assert_eq!(Bson::from(Money::MAX), Bson::Int64(i64::MAX));
+
+        let money: Money = mongodb::bson::from_bson(Bson::Int32(-5)).unwrap();
+
+        assert_eq!(money, Money(-5));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::{
+    app_error::AppError, client::Client, idempotency::IdempotencyRecord, transaction::Transaction,
+};
+use mongodb::{
+    error::{ErrorKind, WriteFailure, RETRYABLE_WRITE_ERROR, TRANSIENT_TRANSACTION_ERROR},
+    options::InsertManyOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tokio::sync::{
+    mpsc::{self, error::TrySendError, Permit, Receiver, Sender},
+    Notify,
+};
+
+/// Everything one request left for MongoDB: the ledger rows, the client
+/// snapshots taken right after them (none in atomic mode) and the journal
+/// entries to commit once both are stored. Changes like a new limit carry a
+/// snapshot without ledger rows.
+pub struct PendingWrite {
+    pub transactions: Vec<Transaction>,
+    pub clients: Vec<Client>,
+    pub seqs: Vec<u64>,
+    pub idempotency_record: Option<IdempotencyRecord>,
+}
+
+impl PendingWrite {
+    pub fn new(transaction: Transaction, client: Option<Client>, seq: u64) -> Self {
+        Self {
+            transactions: vec![transaction],
+            clients: client.into_iter().collect(),
+            seqs: vec![seq],
+            idempotency_record: None,
+        }
+    }
+
+    /// A client change with no ledger row left to write, like a new limit or
+    /// a reversal, whose row is stored before its balance changes.
+    pub fn snapshot(client: Client, seq: u64) -> Self {
+        Self {
+            transactions: Vec::new(),
+            clients: vec![client],
+            seqs: vec![seq],
+            idempotency_record: None,
+        }
+    }
+
+    pub fn with_idempotency_record(mut self, record: Option<IdempotencyRecord>) -> Self {
+        self.idempotency_record = record;
+
+        self
+    }
+
+    pub fn push(&mut self, transaction: Transaction, client: Option<Client>, seq: u64) {
+        self.transactions.push(transaction);
+        self.clients.extend(client);
+        self.seqs.push(seq);
+    }
+}
+
+/// Writes merged by the worker. Only the newest snapshot of each client is
+/// kept, since every snapshot already contains the ones before it.
+#[derive(Default)]
+pub struct Batch {
+    pub transactions: Vec<Transaction>,
+    pub clients: HashMap<i32, Client>,
+    pub seqs: Vec<u64>,
+    pub idempotency_records: Vec<IdempotencyRecord>,
+    pub writes: usize,
+}
+
+impl Batch {
+    pub fn push(&mut self, write: PendingWrite) {
+        self.transactions.extend(write.transactions);
+        self.seqs.extend(write.seqs);
+        self.idempotency_records.extend(write.idempotency_record);
+        self.writes += 1;
+
+        for client in write.clients {
+            self.clients.insert(client._id, client);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PersistenceStatusDTO {
+    #[serde(rename(serialize = "fila"))]
+    pub queued: usize,
+
+    #[serde(rename(serialize = "falhas_pendentes"))]
+    pub failed: usize,
+}
+
+pub struct PersistenceQueue {
+    sender: Sender<PendingWrite>,
+    closed: Notify,
+    capacity: usize,
+    in_flight: AtomicUsize,
+}
+
+impl PersistenceQueue {
+    pub fn new(capacity: usize) -> (Self, Receiver<PendingWrite>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+
+        let queue = Self {
+            sender,
+            closed: Notify::new(),
+            capacity,
+            in_flight: AtomicUsize::new(0),
+        };
+
+        (queue, receiver)
+    }
+
+    /// Claims a slot before the request changes anything, so a full queue
+    /// turns into a 503 instead of an accepted write with nowhere to go.
+    pub fn reserve(&self) -> Result<Permit<'_, PendingWrite>, AppError> {
+        self.sender.try_reserve().map_err(|err| match err {
+            TrySendError::Full(()) | TrySendError::Closed(()) => AppError::Overloaded,
+        })
+    }
+
+    pub fn close(&self) {
+        self.closed.notify_one();
+    }
+
+    pub async fn closed(&self) {
+        self.closed.notified().await
+    }
+
+    /// Writes not in MongoDB yet: the queued ones and the batch the worker
+    /// is writing.
+    pub fn len(&self) -> usize {
+        self.capacity - self.sender.capacity() + self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Keeps a batch taken off the queue counted by `len` until `written`.
+    pub fn writing(&self, writes: usize) {
+        self.in_flight.fetch_add(writes, Ordering::SeqCst);
+    }
+
+    pub fn written(&self, writes: usize) {
+        self.in_flight.fetch_sub(writes, Ordering::SeqCst);
+    }
+}
+
+/// Inserts the documents that are not stored yet. Retries and replays may
+/// resend rows that already made it, and since their ids are stable the
+/// duplicates can be skipped.
+pub async fn insert_new<T>(collection: &Collection<T>, docs: &[T]) -> Result<(), AppError>
+where
+    T: Serialize,
+{
+    if docs.is_empty() {
+        return Ok(());
+    }
+
+    let opts = InsertManyOptions::builder().ordered(false).build();
+
+    match collection.insert_many(docs, opts).await {
+        Ok(_) => Ok(()),
+        Err(err) if is_only_duplicates(&err) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    matches!(
+        err.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 11000
+    )
+}
+
+fn is_only_duplicates(err: &mongodb::error::Error) -> bool {
+    match err.kind.as_ref() {
+        ErrorKind::BulkWrite(failure) => {
+            failure.write_concern_error.is_none()
+                && failure
+                    .write_errors
+                    .as_ref()
+                    .is_some_and(|errors| errors.iter().all(|error| error.code == 11000))
+        }
+        _ => is_duplicate_key(err),
+    }
+}
+
+// Errors worth another attempt: the server was unreachable or said so itself.
+pub fn is_transient(err: &AppError) -> bool {
+    let AppError::MongoError(err) = err else {
+        return false;
+    };
+
+    matches!(
+        err.kind.as_ref(),
+        ErrorKind::Io(_)
+            | ErrorKind::ConnectionPoolCleared { .. }
+            | ErrorKind::ServerSelection { .. }
+    ) || err.contains_label(RETRYABLE_WRITE_ERROR)
+        || err.contains_label(TRANSIENT_TRANSACTION_ERROR)
+}
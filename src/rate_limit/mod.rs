@@ -0,0 +1,225 @@
+use crate::{app_config::Config, app_error::AppError, app_state::AppState, utils::Slots};
+use axum::{
+    extract::{Path, State},
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Client ids are folded onto this many buckets, so the shared memory stays
+/// the same size however many ids are seen. Ids that land on the same slot
+/// share their budget.
+const CLIENT_SLOTS: usize = 65536;
+
+const GLOBAL_SLOT: usize = CLIENT_SLOTS;
+
+const MICROS: u64 = 1_000_000;
+
+/// Token bucket kept as a single number, the time at which the bucket would
+/// be full again (GCRA). The wall clock is used instead of `Instant` so every
+/// instance reads it the same way.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    interval: u64,
+    tolerance: u64,
+}
+
+impl Bucket {
+    fn new(rate: f64, burst: u32) -> Option<Self> {
+        (rate > 0.0).then(|| {
+            let interval = ((MICROS as f64 / rate) as u64).max(1);
+
+            Self {
+                interval,
+                tolerance: interval.saturating_mul(burst as u64),
+            }
+        })
+    }
+
+    /// Takes one request, or returns how many seconds until there is room.
+    fn take(&self, slot: &AtomicU64, now: u64) -> Result<(), u64> {
+        let mut full_at = slot.load(Ordering::SeqCst);
+
+        loop {
+            let updated = full_at.max(now).saturating_add(self.interval);
+            let ahead = updated - now;
+
+            if ahead > self.tolerance {
+                return Err((ahead - self.tolerance).div_ceil(MICROS).max(1));
+            }
+
+            match slot.compare_exchange_weak(full_at, updated, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Ok(()),
+                Err(current) => full_at = current,
+            }
+        }
+    }
+
+    /// Gives back a request `take` let through.
+    fn refund(&self, slot: &AtomicU64) {
+        let _ = slot.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |full_at| {
+            Some(full_at.saturating_sub(self.interval))
+        });
+    }
+}
+
+/// Buckets per client id and for the whole service. Their state lives in a
+/// fixed table in shared memory, so instances sharing `/dev/shm` draw from
+/// the same budget.
+pub struct RateLimiter {
+    client: Option<Bucket>,
+    global: Option<Bucket>,
+    slots: Option<Slots>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        let client = Bucket::new(config.client_rate_limit, config.client_rate_burst);
+        let global = Bucket::new(config.global_rate_limit, config.global_rate_burst);
+
+        let slots = (client.is_some() || global.is_some())
+            .then(|| Slots::new(&config.shm_prefix, "rate-buckets", CLIENT_SLOTS + 1));
+
+        Self {
+            client,
+            global,
+            slots,
+        }
+    }
+
+    // The client bucket is charged first and refunded when the global one
+    // refuses, so a request turned away by the global limit does not cost
+    // its client anything.
+    pub fn acquire(&self, client: Option<i32>) -> Result<(), AppError> {
+        let Some(slots) = &self.slots else {
+            return Ok(());
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let client = match (self.client, client) {
+            (Some(bucket), Some(id)) => {
+                let slot = slots.get(id as u32 as usize % CLIENT_SLOTS);
+
+                bucket.take(slot, now).map_err(AppError::RateLimited)?;
+
+                Some((bucket, slot))
+            }
+            _ => None,
+        };
+
+        if let Some(bucket) = self.global {
+            if let Err(wait) = bucket.take(slots.get(GLOBAL_SLOT), now) {
+                if let Some((bucket, slot)) = client {
+                    bucket.refund(slot);
+                }
+
+                return Err(AppError::RateLimited(wait));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Charges the global bucket and, on routes with a client id, that client's
+/// bucket before the request reaches its handler.
+pub async fn enforce<B>(
+    State(app_state): State<Arc<AppState>>,
+    params: Option<Path<HashMap<String, String>>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, AppError> {
+    let client = params
+        .as_ref()
+        .and_then(|Path(params)| params.get("id"))
+        .and_then(|id| id.parse().ok());
+
+    app_state.rate_limiter.acquire(client)?;
+
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: u64 = 1_000 * MICROS;
+
+    #[test]
+    fn burst_then_refused() {
+        let bucket = Bucket::new(10.0, 5).unwrap();
+        let slot = AtomicU64::new(0);
+
+        for _ in 0..5 {
+            assert_eq!(bucket.take(&slot, START), Ok(()));
+        }
+
+        assert_eq!(bucket.take(&slot, START), Err(1));
+    }
+
+    #[test]
+    fn refills_one_request_per_interval() {
+        let bucket = Bucket::new(10.0, 2).unwrap();
+        let slot = AtomicU64::new(0);
+
+        assert_eq!(bucket.take(&slot, START), Ok(()));
+        assert_eq!(bucket.take(&slot, START), Ok(()));
+        assert!(bucket.take(&slot, START).is_err());
+
+        let later = START + bucket.interval;
+
+        assert_eq!(bucket.take(&slot, later), Ok(()));
+        assert!(bucket.take(&slot, later).is_err());
+
+        // An idle bucket fills up to its burst and no further.
+        let idle = later + 10 * MICROS;
+
+        assert_eq!(bucket.take(&slot, idle), Ok(()));
+        assert_eq!(bucket.take(&slot, idle), Ok(()));
+        assert!(bucket.take(&slot, idle).is_err());
+    }
+
+    #[test]
+    fn retry_after_rounds_up_to_whole_seconds() {
+        let bucket = Bucket::new(0.25, 1).unwrap();
+        let slot = AtomicU64::new(0);
+
+        assert_eq!(bucket.take(&slot, START), Ok(()));
+        assert_eq!(bucket.take(&slot, START), Err(4));
+        assert_eq!(bucket.take(&slot, START + 1_500_000), Err(3));
+        assert_eq!(bucket.take(&slot, START + 3_999_999), Err(1));
+        assert_eq!(bucket.take(&slot, START + 4 * MICROS), Ok(()));
+    }
+
+    #[test]
+    fn refund_gives_the_request_back() {
+        let bucket = Bucket::new(1.0, 2).unwrap();
+        let slot = AtomicU64::new(0);
+
+        assert_eq!(bucket.take(&slot, START), Ok(()));
+        assert_eq!(bucket.take(&slot, START), Ok(()));
+        assert!(bucket.take(&slot, START).is_err());
+
+        bucket.refund(&slot);
+
+        assert_eq!(bucket.take(&slot, START), Ok(()));
+        assert!(bucket.take(&slot, START).is_err());
+    }
+
+    #[test]
+    fn zero_rate_disables_the_bucket() {
+        assert!(Bucket::new(0.0, 10).is_none());
+    }
+}
@@ -0,0 +1,395 @@
+use crate::{
+    app_config::PersistenceMode,
+    app_error::AppError,
+    client::Client,
+    currency,
+    money::Money,
+    transaction::{Kind, Transaction},
+    utils::{Cache, Semaphore},
+};
+use chrono::prelude::*;
+use mongodb::{
+    bson::{doc, to_bson, Document},
+    options::FindOptions,
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+#[derive(Deserialize, Debug)]
+struct LedgerKey {
+    client: i32,
+
+    #[serde(default)]
+    currency: Option<String>,
+}
+
+/// Net movement of one account according to the `transactions` collection.
+#[derive(Deserialize, Debug)]
+struct LedgerTotal {
+    _id: LedgerKey,
+
+    net: Money,
+}
+
+/// Ledger totals per client, then per currency.
+type Ledger = HashMap<i32, HashMap<Option<String>, Money>>;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Discrepancy {
+    /// The stored balance is not what the ledger adds up to.
+    Balance {
+        client: i32,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        currency: Option<String>,
+
+        stored: Money,
+
+        expected: Money,
+    },
+
+    /// The shared memory copy disagrees with the `clients` collection.
+    CachedBalance {
+        client: i32,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        currency: Option<String>,
+
+        cached: Money,
+
+        stored: Money,
+    },
+
+    /// `latest_transactions` is not the head of the ledger. Entries are
+    /// listed by id, newest first.
+    LatestTransactions {
+        client: i32,
+
+        stored: Vec<String>,
+
+        expected: Vec<String>,
+    },
+}
+
+/// What a discrepancy is about, regardless of the amounts involved.
+pub type DiscrepancyKey = (&'static str, i32, Option<String>);
+
+impl Discrepancy {
+    fn key(&self) -> DiscrepancyKey {
+        match self {
+            Discrepancy::Balance {
+                client, currency, ..
+            } => ("balance", *client, currency.clone()),
+            Discrepancy::CachedBalance {
+                client, currency, ..
+            } => ("cached_balance", *client, currency.clone()),
+            Discrepancy::LatestTransactions { client, .. } => {
+                ("latest_transactions", *client, None)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Report {
+    pub generated_at: String,
+
+    pub clients: usize,
+
+    pub discrepancies: Vec<Discrepancy>,
+
+    /// Clients with no opening balance, whose default currency was skipped.
+    pub unverified: usize,
+
+    pub repaired: usize,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self {
+            generated_at: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            clients: 0,
+            discrepancies: Vec::new(),
+            unverified: 0,
+            repaired: 0,
+        }
+    }
+}
+
+impl Report {
+    /// Keeps the discrepancies the previous run also found, which `seen`
+    /// holds, and leaves the ones found by this run in `seen` for the next.
+    pub fn confirm(&mut self, seen: &mut HashSet<DiscrepancyKey>) {
+        let found = self.discrepancies.iter().map(Discrepancy::key).collect();
+
+        self.discrepancies
+            .retain(|discrepancy| seen.contains(&discrepancy.key()));
+
+        *seen = found;
+    }
+
+    /// Appends the report to `path` as one JSON line.
+    pub async fn write(&self, path: &str) -> Result<(), AppError> {
+        let mut line = serde_json::to_vec(self)?;
+
+        line.push(b'\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        file.write_all(&line).await?;
+        file.sync_data().await?;
+
+        Ok(())
+    }
+}
+
+/// How many entries `latest_transactions` should hold for a client. Atomic
+/// updates slice every client to the configured length.
+pub fn window(mode: PersistenceMode, default: usize) -> impl Fn(&Client) -> usize {
+    move |client| match mode {
+        PersistenceMode::Atomic => default,
+        _ => client.window(default),
+    }
+}
+
+/// Compares every client in MongoDB with its ledger. `window` gives the
+/// number of entries `latest_transactions` is expected to hold, and `cache`,
+/// when given, also checks the shared memory copy of each client. With
+/// `repair` the stored balances are overwritten with the ledger ones, which
+/// is only right while no accepted write is still missing from the ledger.
+pub async fn run(
+    db: &Database,
+    window: impl Fn(&Client) -> usize,
+    cache: Option<(&Cache, &Semaphore)>,
+    repair: bool,
+) -> Result<Report, AppError> {
+    let ledger = ledger(db).await?;
+
+    let clients = db.collection::<Client>("clients");
+    let transactions = db.collection::<Transaction>("transactions");
+
+    let mut cursor = clients.find(None, None).await?;
+    let mut report = Report::default();
+
+    while cursor.advance().await? {
+        let client = cursor.deserialize_current()?;
+
+        report.clients += 1;
+
+        if client.opening_balance.is_none() {
+            report.unverified += 1;
+        }
+
+        let balances = compare(&client, ledger.get(&client._id))?;
+
+        if repair {
+            for discrepancy in &balances {
+                let Discrepancy::Balance {
+                    currency, expected, ..
+                } = discrepancy
+                else {
+                    continue;
+                };
+
+                let mut set = Document::new();
+
+                set.insert(currency::balance_field(currency.as_deref()), *expected);
+
+                clients
+                    .update_one(doc! { "_id": client._id }, doc! { "$set": set }, None)
+                    .await?;
+
+                report.repaired += 1;
+            }
+        }
+
+        report.discrepancies.extend(balances);
+
+        let latest = transactions
+            .find(
+                doc! { "client": client._id },
+                FindOptions::builder()
+                    .sort(doc! { "date": -1, "_id": -1 })
+                    .limit(window(&client) as i64)
+                    .build(),
+            )
+            .await?;
+
+        report
+            .discrepancies
+            .extend(compare_latest(&client, collect(latest).await?));
+
+        if let Some((cache, semaphore)) = cache {
+            let key = client._id.to_string();
+
+            semaphore.wait(&key).await;
+
+            let cached = cache.get::<Client>(&key).await;
+
+            semaphore.release(&key).await;
+
+            if let Some(cached) = cached {
+                report
+                    .discrepancies
+                    .extend(compare_cached(&client, &cached));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+async fn ledger(db: &Database) -> Result<Ledger, AppError> {
+    let mut ledger = Ledger::new();
+
+    let mut totals = db
+        .collection::<Transaction>("transactions")
+        .aggregate(ledger_pipeline()?, None)
+        .await?
+        .with_type::<LedgerTotal>();
+
+    while totals.advance().await? {
+        let total = totals.deserialize_current()?;
+
+        ledger
+            .entry(total._id.client)
+            .or_default()
+            .insert(total._id.currency, total.net);
+    }
+
+    Ok(ledger)
+}
+
+async fn collect(mut cursor: mongodb::Cursor<Transaction>) -> Result<Vec<Transaction>, AppError> {
+    let mut transactions = Vec::new();
+
+    while cursor.advance().await? {
+        transactions.push(cursor.deserialize_current()?);
+    }
+
+    Ok(transactions)
+}
+
+// Credits minus debits per client and currency.
+fn ledger_pipeline() -> Result<Vec<Document>, mongodb::bson::ser::Error> {
+    Ok(vec![doc! {
+        "$group": {
+            "_id": { "client": "$client", "currency": "$currency" },
+            "net": {
+                "$sum": {
+                    "$cond": [
+                        { "$eq": ["$kind", to_bson(&Kind::C)?] },
+                        "$value",
+                        { "$multiply": ["$value", -1] },
+                    ],
+                },
+            },
+        },
+    }])
+}
+
+/// Compares every account of the client with its ledger totals. The opening
+/// balance given at creation never went through a transaction, so it is added
+/// back to the default currency, which is left out when it is unknown.
+fn compare(
+    client: &Client,
+    totals: Option<&HashMap<Option<String>, Money>>,
+) -> Result<Vec<Discrepancy>, AppError> {
+    let empty = HashMap::new();
+    let totals = totals.unwrap_or(&empty);
+
+    let net =
+        |currency: Option<&String>| totals.get(&currency.cloned()).copied().unwrap_or_default();
+
+    let mut accounts = Vec::new();
+
+    if let Some(opening_balance) = client.opening_balance {
+        accounts.push((
+            None,
+            client.balance,
+            opening_balance.checked_add(net(None))?,
+        ));
+    }
+
+    for (currency, account) in &client.currencies {
+        accounts.push((Some(currency.clone()), account.balance, net(Some(currency))));
+    }
+
+    // Movements in a currency the client document does not know about.
+    for (currency, total) in totals {
+        if let Some(currency) = currency {
+            if !client.currencies.contains_key(currency) {
+                accounts.push((Some(currency.clone()), Money::ZERO, *total));
+            }
+        }
+    }
+
+    Ok(accounts
+        .into_iter()
+        .filter(|(_, stored, expected)| stored != expected)
+        .map(|(currency, stored, expected)| Discrepancy::Balance {
+            client: client._id,
+            currency,
+            stored,
+            expected,
+        })
+        .collect())
+}
+
+fn compare_latest(client: &Client, latest: Vec<Transaction>) -> Option<Discrepancy> {
+    let stored: Vec<String> = client
+        .latest_transactions
+        .iter()
+        .map(|transaction| transaction.id.clone().unwrap_or_default())
+        .collect();
+
+    let expected: Vec<String> = latest
+        .into_iter()
+        .map(|transaction| transaction._id)
+        .collect();
+
+    // Entries written before ids were kept in the snapshot have none, so
+    // only their position can be checked.
+    let matches = stored.len() == expected.len()
+        && stored
+            .iter()
+            .zip(&expected)
+            .all(|(stored, expected)| stored.is_empty() || stored == expected);
+
+    (!matches).then_some(Discrepancy::LatestTransactions {
+        client: client._id,
+        stored,
+        expected,
+    })
+}
+
+fn compare_cached(client: &Client, cached: &Client) -> Vec<Discrepancy> {
+    let mut accounts = vec![(None, cached.balance, client.balance)];
+
+    for (currency, account) in &cached.currencies {
+        let stored = client
+            .currencies
+            .get(currency)
+            .map_or(Money::ZERO, |account| account.balance);
+
+        accounts.push((Some(currency.clone()), account.balance, stored));
+    }
+
+    accounts
+        .into_iter()
+        .filter(|(_, cached, stored)| cached != stored)
+        .map(|(currency, cached, stored)| Discrepancy::CachedBalance {
+            client: client._id,
+            currency,
+            cached,
+            stored,
+        })
+        .collect()
+}
@@ -1,12 +1,36 @@
-use crate::{balance::BalanceDTO, transaction::TransactionDTO};
+use crate::{app_error::AppError, balance::BalanceDTO, transaction::TransactionDTO};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+pub const MAX_STATEMENT_LEN: usize = 100;
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StatementDTO {
     #[serde(rename(serialize = "saldo"))]
     pub balance: BalanceDTO,
 
     #[serde(rename(serialize = "ultimas_transacoes"))]
     pub latest_transactions: Vec<TransactionDTO>,
+
+    #[serde(
+        rename(serialize = "saldos_por_moeda"),
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub currency_balances: Vec<BalanceDTO>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct StatementQuery {
+    #[serde(rename = "limite")]
+    pub limit: Option<usize>,
+}
+
+impl StatementQuery {
+    pub fn size(&self, window: usize) -> Result<usize, AppError> {
+        match self.limit {
+            None => Ok(window),
+            Some(limit) if (1..=MAX_STATEMENT_LEN).contains(&limit) => Ok(limit),
+            Some(_) => Err(AppError::InvalidQueryParam("limite")),
+        }
+    }
 }
@@ -0,0 +1,67 @@
+use crate::app_config::{Config, OtelProtocol};
+use opentelemetry::{
+    global,
+    trace::{TraceError, TracerProvider as _},
+    KeyValue,
+};
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
+use opentelemetry_sdk::{
+    export::trace::SpanExporter,
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{self, Tracer, TracerProvider},
+    Resource,
+};
+
+/// Sets up the OTLP exporter when an endpoint is configured. Incoming
+/// `traceparent` headers are honoured either way.
+pub fn tracer(config: &Config) -> Result<Option<Tracer>, TraceError> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let Some(endpoint) = &config.otel_endpoint else {
+        return Ok(None);
+    };
+
+    let exporter: SpanExporterBuilder = match config.otel_protocol {
+        OtelProtocol::Grpc => opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(endpoint)
+            .into(),
+        OtelProtocol::Http => opentelemetry_otlp::new_exporter()
+            .http()
+            .with_endpoint(endpoint)
+            .into(),
+    };
+
+    Ok(Some(tracer_with(
+        exporter.build_span_exporter()?,
+        &config.otel_service_name,
+    )))
+}
+
+/// Registers a provider exporting through `exporter` and returns its tracer.
+pub fn tracer_with<E>(exporter: E, service_name: &str) -> Tracer
+where
+    E: SpanExporter + 'static,
+{
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_config(trace::config().with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            service_name.to_string(),
+        )])))
+        .build();
+
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+
+    global::set_tracer_provider(provider);
+
+    tracer
+}
+
+/// Flushes the spans still buffered by the batch exporter.
+pub async fn shutdown() {
+    // Shutting down blocks until the exporter is done, which needs the
+    // runtime to keep going.
+    let _ = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await;
+}
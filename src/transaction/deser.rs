@@ -1,12 +1,13 @@
 use super::Kind;
+use crate::{currency, money::Money};
 use serde::{Deserialize, Deserializer};
 
-pub fn deserialize_value<'de, D>(deserializer: D) -> Result<i32, D::Error>
+pub fn deserialize_value<'de, D>(deserializer: D) -> Result<Money, D::Error>
 where
     D: Deserializer<'de>,
 {
-    if let Ok(value) = i32::deserialize(deserializer) {
-        if value > 0 {
+    if let Ok(value) = Money::deserialize(deserializer) {
+        if value.is_positive() {
             return Ok(value);
         }
     }
@@ -42,3 +43,18 @@ where
         )),
     }
 }
+
+pub fn deserialize_currency<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    if let Ok(value) = String::deserialize(deserializer) {
+        if currency::is_valid_code(&value) {
+            return Ok(Some(value));
+        }
+    }
+
+    Err(serde::de::Error::custom(
+        "Campo 'moeda' deve ser um código de 3 letras maiúsculas.",
+    ))
+}
@@ -1,25 +1,25 @@
-mod deser;
+pub mod deser;
+use crate::money::Money;
 use chrono::prelude::*;
+use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 use speedy::{Readable, Writable};
 
-#[derive(Serialize, Deserialize, Debug, Clone, Readable, Writable)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Readable, Writable)]
 #[serde(rename_all = "lowercase")]
-
 pub enum Kind {
     C,
     D,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Readable, Writable)]
-
 pub struct TransactionDTO {
     #[serde(
         alias = "valor",
         rename(serialize = "valor"),
         deserialize_with = "deser::deserialize_value"
     )]
-    pub value: i32,
+    pub value: Money,
 
     #[serde(
         alias = "tipo",
@@ -37,32 +37,74 @@ pub struct TransactionDTO {
 
     #[serde(rename(serialize = "realizada_em"), default = "default_date")]
     pub date: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+
+    #[serde(
+        alias = "moeda",
+        rename(serialize = "moeda"),
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser::deserialize_currency"
+    )]
+    pub currency: Option<String>,
+
+    #[serde(
+        alias = "transferencia",
+        rename(serialize = "transferencia"),
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub transfer: Option<String>,
 }
 
 fn default_date() -> String {
     Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true)
 }
 
+pub fn new_id() -> String {
+    ObjectId::new().to_hex()
+}
+
 #[derive(Readable, Writable, Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
+    #[serde(
+        default = "new_id",
+        with = "mongodb::bson::serde_helpers::hex_string_as_object_id"
+    )]
+    pub _id: String,
+
     pub client: i32,
 
-    pub value: i32,
+    pub value: Money,
 
     pub kind: Kind,
 
     pub description: String,
 
     pub date: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transfer: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverses: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
 }
 
-impl Into<TransactionDTO> for Transaction {
-    fn into(self) -> TransactionDTO {
-        TransactionDTO {
-            value: self.value,
-            kind: self.kind,
-            description: self.description,
-            date: self.date,
+impl From<Transaction> for TransactionDTO {
+    fn from(transaction: Transaction) -> Self {
+        Self {
+            value: transaction.value,
+            kind: transaction.kind,
+            description: transaction.description,
+            date: transaction.date,
+            id: Some(transaction._id),
+            currency: transaction.currency,
+            transfer: transaction.transfer,
         }
     }
 }
@@ -70,20 +112,66 @@ impl Into<TransactionDTO> for Transaction {
 impl Transaction {
     pub fn new(client: i32, transaction_dto: TransactionDTO) -> Self {
         Self {
+            _id: new_id(),
             client,
             value: transaction_dto.value,
             kind: transaction_dto.kind,
             description: transaction_dto.description,
             date: transaction_dto.date,
+            transfer: None,
+            reverses: None,
+            currency: transaction_dto.currency,
         }
     }
+
+    pub fn reversal(&self) -> Self {
+        Self {
+            _id: new_id(),
+            client: self.client,
+            value: self.value,
+            kind: match self.kind {
+                Kind::C => Kind::D,
+                Kind::D => Kind::C,
+            },
+            description: String::from("estorno"),
+            date: default_date(),
+            transfer: None,
+            reverses: Some(self._id.clone()),
+            currency: self.currency.clone(),
+        }
+    }
+
+    pub fn with_transfer(mut self, transfer: &str) -> Self {
+        self.transfer = Some(transfer.to_string());
+
+        self
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Readable, Writable)]
 pub struct TransactionResponse {
-    #[serde(rename(serialize = "saldo"))]
-    pub balance: i32,
+    #[serde(alias = "saldo", rename(serialize = "saldo"))]
+    pub balance: Money,
+
+    #[serde(alias = "limite", rename(serialize = "limite"))]
+    pub limit: Money,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
 
-    #[serde(rename(serialize = "limite"))]
-    pub limit: i32,
+    #[serde(
+        alias = "moeda",
+        rename(serialize = "moeda"),
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub currency: Option<String>,
+}
+
+impl TransactionResponse {
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+
+        self
+    }
 }
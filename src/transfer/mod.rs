@@ -0,0 +1,85 @@
+use crate::{
+    client::Client,
+    money::Money,
+    transaction::{deser, Kind, Transaction, TransactionDTO, TransactionResponse},
+};
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferDTO {
+    #[serde(alias = "origem", rename(serialize = "origem"))]
+    pub from: i32,
+
+    #[serde(alias = "destino", rename(serialize = "destino"))]
+    pub to: i32,
+
+    #[serde(
+        alias = "valor",
+        rename(serialize = "valor"),
+        deserialize_with = "deser::deserialize_value"
+    )]
+    pub value: Money,
+
+    #[serde(
+        alias = "descricao",
+        rename(serialize = "descricao"),
+        deserialize_with = "deser::deserialize_description"
+    )]
+    pub description: String,
+
+    #[serde(
+        alias = "moeda",
+        rename(serialize = "moeda"),
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deser::deserialize_currency"
+    )]
+    pub currency: Option<String>,
+}
+
+impl TransferDTO {
+    pub fn debit(&self, date: &str) -> TransactionDTO {
+        self.leg(Kind::D, date)
+    }
+
+    pub fn credit(&self, date: &str) -> TransactionDTO {
+        self.leg(Kind::C, date)
+    }
+
+    fn leg(&self, kind: Kind, date: &str) -> TransactionDTO {
+        TransactionDTO {
+            value: self.value,
+            kind,
+            description: self.description.clone(),
+            date: date.to_string(),
+            id: None,
+            currency: self.currency.clone(),
+            transfer: None,
+        }
+    }
+
+    pub fn date() -> String {
+        Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true)
+    }
+}
+
+/// One side of a transfer. Both sides share the journal entry they were
+/// written in; atomic transfers are already stored when they return, so they
+/// leave no entry to commit.
+pub struct TransferLeg {
+    pub transaction: Transaction,
+    pub client: Client,
+    pub seq: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferResponse {
+    pub id: String,
+
+    #[serde(alias = "origem", rename(serialize = "origem"))]
+    pub from: TransactionResponse,
+
+    #[serde(alias = "destino", rename(serialize = "destino"))]
+    pub to: TransactionResponse,
+}
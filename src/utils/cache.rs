@@ -4,11 +4,12 @@ use std::collections::HashMap;
 use tokio::sync::RwLock;
 
 pub struct Cache {
+    prefix: String,
     inner: RwLock<HashMap<String, Mmap>>,
 }
 
 impl Cache {
-    pub fn new() -> Self {
+    pub fn new(prefix: &str) -> Self {
         let inner = RwLock::new(HashMap::new());
 
         let paths = std::fs::read_dir("/dev/shm").unwrap();
@@ -18,25 +19,36 @@ impl Cache {
             let file_name = entry.file_name();
             let file_name_str = file_name.to_string_lossy();
 
-            if file_name_str.starts_with("dk-rinha") || file_name_str.starts_with("sem.dk-rinha") {
+            if file_name_str.starts_with(prefix)
+                || file_name_str.starts_with(&format!("sem.{prefix}"))
+            {
                 let file_path = entry.path();
 
                 let _ = std::fs::remove_file(&file_path);
             }
         }
 
-        Self { inner }
+        Self {
+            prefix: prefix.to_string(),
+            inner,
+        }
+    }
+
+    pub fn probe(&self) -> std::io::Result<()> {
+        Mmap::probe(&self.prefix, "health")
     }
 
+    #[tracing::instrument(level = "debug", name = "cache_get", skip(self))]
     pub async fn get<'a, T>(&self, key: &str) -> Option<T>
     where
         T: Readable<'a, LittleEndian>,
     {
         let value = self.read(key).await;
 
-        return T::read_from_buffer(value).ok();
+        T::read_from_buffer(value).ok()
     }
 
+    #[tracing::instrument(level = "debug", name = "cache_insert", skip(self, value))]
     pub async fn insert<'a, T>(&self, key: &str, value: &T) -> Option<T>
     where
         T: Writable<LittleEndian> + Readable<'a, LittleEndian>,
@@ -48,10 +60,10 @@ impl Cache {
     }
 
     async fn init<'a>(&self, key: &str, bytes: Option<&[u8]>) -> &'a [u8] {
-        let mmap = Mmap::new(&format!("cache-{key}"));
+        let mmap = Mmap::new(&self.prefix, &format!("cache-{key}"));
 
         if let Some(bytes) = bytes {
-            mmap.write(bytes);
+            Self::store(&mmap, key, bytes);
         }
 
         let mut guard = self.inner.write().await;
@@ -73,11 +85,19 @@ impl Cache {
         if let Some(mmap) = self.inner.read().await.get(key) {
             let prev = mmap.read();
 
-            mmap.write(bytes);
+            Self::store(mmap, key, bytes);
 
             return prev;
         }
 
         return self.init(key, Some(bytes)).await;
     }
+
+    // A value that cannot be stored leaves the key empty, so the next read
+    // misses and goes to MongoDB.
+    fn store(mmap: &Mmap, key: &str, bytes: &[u8]) {
+        if let Err(err) = mmap.write(bytes) {
+            tracing::warn!(error = %err, key, "could not write cache entry");
+        }
+    }
 }
@@ -1,28 +1,38 @@
 use nix::libc::{
-    c_void, close, ftruncate, memcpy, mmap, mremap, off_t, shm_open, shm_unlink, size_t,
-    MAP_SHARED, MREMAP_MAYMOVE, O_CREAT, O_RDWR, PROT_READ, PROT_WRITE,
+    c_void, close, memcpy, mmap, off_t, posix_fallocate, shm_open, shm_unlink, size_t, MAP_FAILED,
+    MAP_SHARED, O_CREAT, O_RDWR, PROT_READ, PROT_WRITE,
 };
 use std::{
     ffi::CString,
-    ptr,
-    sync::atomic::{AtomicPtr, Ordering},
+    io, ptr,
+    sync::atomic::{AtomicPtr, AtomicU32, Ordering},
 };
 
+/// A value in shared memory, with its length kept in a second object.
+///
+/// The whole `MAX_LENGTH` is mapped up front so the address never moves,
+/// and the object behind it only ever grows, which keeps a value written by
+/// another instance readable without remapping.
 pub struct Mmap {
     name: CString,
+    fd: i32,
     address: AtomicPtr<c_void>,
     length_address: AtomicPtr<c_void>,
+    // How much of the object this process knows to be allocated.
+    capacity: AtomicU32,
 }
 
 impl Mmap {
     const INIT_LENGTH: u32 = 4;
 
-    pub fn new(name: &str) -> Self {
-        let (length, length_address) = Self::init_length(name);
+    pub const MAX_LENGTH: u32 = 1024 * 1024;
 
-        let name = Self::mmap_name(name);
+    pub fn new(prefix: &str, name: &str) -> Self {
+        let (length, length_address) = Self::init_length(prefix, name);
+
+        let name = Self::mmap_name(prefix, name);
         let fd = Self::open_shared_memory(&name, length);
-        let address = Self::map_to_memory(fd, length);
+        let address = Self::map_to_memory(fd, Self::MAX_LENGTH);
 
         let atomic_ptr = AtomicPtr::new(std::ptr::null_mut());
 
@@ -30,24 +40,47 @@ impl Mmap {
 
         Self {
             name,
+            fd,
             address: atomic_ptr,
             length_address,
+            capacity: AtomicU32::new(length),
         }
     }
 
-    fn mmap_name(name: &str) -> CString {
-        CString::new(format!("/dk-rinha-2024-mmap-{name}")).unwrap()
+    /// Opens and removes a throwaway object, telling whether shared memory
+    /// can be opened at all without panicking like `new` does.
+    pub fn probe(prefix: &str, name: &str) -> std::io::Result<()> {
+        let name = Self::mmap_name(prefix, name);
+
+        let shm_fd = unsafe { shm_open(name.as_ptr(), O_RDWR | O_CREAT, 0o666) };
+
+        if shm_fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        unsafe {
+            close(shm_fd);
+            shm_unlink(name.as_ptr());
+        }
+
+        Ok(())
     }
 
-    fn mmap_length_name(name: &str) -> CString {
-        CString::new(format!("/dk-rinha-2024-mmap-len-{name}")).unwrap()
+    fn mmap_name(prefix: &str, name: &str) -> CString {
+        CString::new(format!("/{prefix}-mmap-{name}")).unwrap()
     }
 
-    fn init_length(name: &str) -> (u32, AtomicPtr<c_void>) {
-        let mmap_length_name = Self::mmap_length_name(name);
+    fn mmap_length_name(prefix: &str, name: &str) -> CString {
+        CString::new(format!("/{prefix}-mmap-len-{name}")).unwrap()
+    }
+
+    fn init_length(prefix: &str, name: &str) -> (u32, AtomicPtr<c_void>) {
+        let mmap_length_name = Self::mmap_length_name(prefix, name);
         let fd = Self::open_shared_memory(&mmap_length_name, 4);
         let length_address = Self::map_to_memory(fd, 4);
 
+        unsafe { close(fd) };
+
         let bytes = unsafe { std::slice::from_raw_parts(length_address as *const u8, 4) };
 
         let length: u32 = match bytes.iter().all(|&b| b == 0) {
@@ -67,7 +100,7 @@ impl Mmap {
 
         length_atomic_ptr.store(length_address, Ordering::SeqCst);
 
-        (length, length_atomic_ptr)
+        (length.min(Self::MAX_LENGTH), length_atomic_ptr)
     }
 
     fn open_shared_memory(name: &CString, length: u32) -> i32 {
@@ -80,40 +113,80 @@ impl Mmap {
             )
         }
 
-        unsafe {
-            ftruncate(shm_fd, length as off_t);
+        // An object another instance already grew keeps its size.
+        if let Err(err) = Self::reserve(shm_fd, length) {
+            panic!("failed to size shared memory: {err}")
         }
 
         shm_fd
     }
 
+    // Grows the object to at least `length` bytes and never shrinks it, so
+    // concurrent writers cannot cut off each other's values.
+    fn reserve(shm_fd: i32, length: u32) -> io::Result<()> {
+        match unsafe { posix_fallocate(shm_fd, 0, length as off_t) } {
+            0 => Ok(()),
+            code => Err(io::Error::from_raw_os_error(code)),
+        }
+    }
+
     fn map_to_memory(shm_fd: i32, len: u32) -> *mut c_void {
-        unsafe {
-            let addr = mmap(
+        let addr = unsafe {
+            mmap(
                 ptr::null_mut(),
                 len as size_t,
                 PROT_READ | PROT_WRITE,
                 MAP_SHARED,
                 shm_fd,
                 0,
-            );
-
-            close(shm_fd);
+            )
+        };
 
-            addr
+        if addr == MAP_FAILED {
+            panic!("failed to map shared memory")
         }
+
+        addr
     }
 
-    pub fn write(&self, bytes: &[u8]) {
-        self.set_length(bytes.len().try_into().unwrap());
+    /// Values over `MAX_LENGTH` are refused and the stored one is cleared,
+    /// so readers miss instead of seeing a stale value.
+    pub fn write(&self, bytes: &[u8]) -> io::Result<()> {
+        let len = match u32::try_from(bytes.len()) {
+            Ok(len) if len <= Self::MAX_LENGTH => len,
+            _ => {
+                self.set_length(0);
+
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} bytes do not fit in {}", bytes.len(), Self::MAX_LENGTH),
+                ));
+            }
+        };
+
+        if len > self.capacity.load(Ordering::SeqCst) {
+            let capacity = len.next_power_of_two().min(Self::MAX_LENGTH);
+
+            if let Err(err) = Self::reserve(self.fd, capacity) {
+                self.set_length(0);
+
+                return Err(err);
+            }
+
+            self.capacity.fetch_max(capacity, Ordering::SeqCst);
+        }
 
         unsafe {
             memcpy(
                 self.address.load(Ordering::SeqCst),
                 bytes.as_ptr() as *const c_void,
-                self.get_length() as size_t,
+                len as size_t,
             );
         };
+
+        self.set_length(len);
+
+        Ok(())
     }
 
     pub fn read<'a>(&self) -> &'a [u8] {
@@ -125,7 +198,7 @@ impl Mmap {
         }
     }
 
-    pub fn set_length<'a>(&self, len: u32) {
+    fn set_length(&self, len: u32) {
         let bytes = u32::to_le_bytes(len);
 
         unsafe {
@@ -134,17 +207,6 @@ impl Mmap {
                 bytes.as_ptr() as *const c_void,
                 4,
             );
-
-            let addr = mremap(
-                self.address.load(Ordering::SeqCst),
-                self.get_length() as size_t,
-                len as size_t,
-                MREMAP_MAYMOVE,
-            );
-
-            if addr != self.address.load(Ordering::SeqCst) {
-                self.address.store(addr, Ordering::SeqCst)
-            }
         };
     }
 
@@ -153,12 +215,47 @@ impl Mmap {
             std::slice::from_raw_parts(self.length_address.load(Ordering::SeqCst) as *const u8, 4)
         };
 
-        u32::from_le_bytes(bytes.try_into().unwrap())
+        u32::from_le_bytes(bytes.try_into().unwrap()).min(Self::MAX_LENGTH)
     }
 }
 
 impl Drop for Mmap {
     fn drop(&mut self) {
-        unsafe { shm_unlink(self.name.as_ptr()) };
+        unsafe {
+            close(self.fd);
+            shm_unlink(self.name.as_ptr());
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mmap;
+
+    #[test]
+    fn values_larger_than_a_page_grow_the_object() {
+        let prefix = format!("rinha-test-{}", std::process::id());
+
+        let mmap = Mmap::new(&prefix, "grow");
+
+        let small = vec![1u8; 16];
+        let large: Vec<u8> = (0..3 * 4096 + 100).map(|i| i as u8).collect();
+
+        mmap.write(&small).unwrap();
+        mmap.write(&large).unwrap();
+
+        assert_eq!(mmap.read(), &large[..]);
+
+        // Another instance maps the object after it grew.
+        let other = Mmap::new(&prefix, "grow");
+
+        assert_eq!(other.read(), &large[..]);
+
+        let too_large = vec![0u8; Mmap::MAX_LENGTH as usize + 1];
+
+        assert!(mmap.write(&too_large).is_err());
+        assert!(other.read().is_empty());
+
+        let _ = std::fs::remove_file(format!("/dev/shm/{prefix}-mmap-len-grow"));
     }
 }
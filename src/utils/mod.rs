@@ -1,7 +1,9 @@
 mod cache;
 mod mmap;
 mod semaphore;
+mod slots;
 
 pub use cache::Cache;
 pub use mmap::Mmap;
 pub use semaphore::Semaphore;
+pub use slots::Slots;
@@ -1,54 +1,140 @@
-use nix::libc::{sem_open, sem_post, sem_t, sem_wait, O_CREAT, O_RDWR, SEM_FAILED};
-use std::{
-    collections::HashMap,
-    ffi::CString,
-    sync::atomic::{AtomicPtr, Ordering},
+use nix::libc::{
+    sem_close, sem_open, sem_post, sem_t, sem_trywait, sem_unlink, sem_wait, O_CREAT, O_RDWR,
+    SEM_FAILED,
 };
-use tokio::sync::RwLock;
+use prometheus::Histogram;
+use std::{collections::HashMap, ffi::CString, sync::RwLock};
+use tokio::task::JoinHandle;
+
+/// Handle to a named semaphore. They live in shared memory and are meant to
+/// be used from any thread of any process, so the pointer can be shared.
+#[derive(Clone, Copy)]
+struct NamedSem(*mut sem_t);
+
+unsafe impl Send for NamedSem {}
+unsafe impl Sync for NamedSem {}
+
+impl NamedSem {
+    fn post(self) {
+        unsafe { sem_post(self.0) };
+    }
+
+    fn wait(self) {
+        // Only interrupted by signals, which do not give up the wait.
+        while unsafe { sem_wait(self.0) } != 0 {}
+    }
+}
+
+/// A `sem_wait` running on the blocking pool. It cannot be cancelled, so if
+/// the task waiting on it goes away the semaphore is posted back once it is
+/// acquired, instead of staying locked for good.
+struct BlockedWait {
+    sem: NamedSem,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for BlockedWait {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let sem = self.sem;
+
+            tokio::spawn(async move {
+                if handle.await.is_ok() {
+                    sem.post();
+                }
+            });
+        }
+    }
+}
 
 pub struct Semaphore {
-    sems: RwLock<HashMap<String, AtomicPtr<sem_t>>>,
+    prefix: String,
+    sems: RwLock<HashMap<String, NamedSem>>,
+    wait_time: Histogram,
 }
 
 impl Semaphore {
-    pub fn new() -> Self {
+    pub fn new(prefix: &str, wait_time: Histogram) -> Self {
         let sems = RwLock::new(HashMap::new());
 
-        Self { sems }
+        Self {
+            prefix: prefix.to_string(),
+            sems,
+            wait_time,
+        }
     }
 
     pub async fn release(&self, key: &str) {
-        if let Some(sem) = self.sems.read().await.get(key) {
-            unsafe { sem_post(sem.load(Ordering::SeqCst)) };
+        let sem = self.sems.read().unwrap().get(key).copied();
+
+        if let Some(sem) = sem {
+            sem.post();
         }
     }
 
+    // `sem_wait` would block a runtime thread until another task, maybe one
+    // queued behind it on that same thread, posts, so a contended lock is
+    // waited for on the blocking pool instead. Waiters queue in the kernel
+    // and are woken as soon as the holder posts, and no map guard is held
+    // while waiting.
+    #[tracing::instrument(level = "debug", name = "semaphore_wait", skip(self))]
     pub async fn wait(&self, key: &str) {
-        if let Some(sem) = self.sems.read().await.get(key) {
-            unsafe { sem_wait(sem.load(Ordering::SeqCst)) };
+        let _timer = self.wait_time.start_timer();
 
+        let sem = self.get_or_open(key);
+
+        if unsafe { sem_trywait(sem.0) } == 0 {
             return;
         }
 
-        let sem = unsafe {
-            let sem = Self::init(key);
+        let mut blocked = BlockedWait {
+            sem,
+            handle: Some(tokio::task::spawn_blocking(move || sem.wait())),
+        };
 
-            sem_wait(sem.load(Ordering::SeqCst));
+        if let Some(handle) = blocked.handle.as_mut() {
+            let _ = handle.await;
+        }
 
-            sem
-        };
+        blocked.handle = None;
+    }
+
+    fn get_or_open(&self, key: &str) -> NamedSem {
+        if let Some(sem) = self.sems.read().unwrap().get(key) {
+            return *sem;
+        }
 
-        self.sems.write().await.insert(key.to_string(), sem);
+        *self
+            .sems
+            .write()
+            .unwrap()
+            .entry(key.to_string())
+            .or_insert_with(|| Self::init(&self.prefix, key))
     }
 
-    fn init(key: &str) -> AtomicPtr<sem_t> {
-        let name = CString::new(format!("/dk-rinha-2024-sem-{key}")).unwrap();
-        let semaphore = Self::open_semaphore(&name);
-        let atomic_ptr = AtomicPtr::new(std::ptr::null_mut());
+    /// Opens and removes a throwaway semaphore, telling whether named
+    /// semaphores can be opened at all without panicking like `wait` does.
+    pub fn probe(&self) -> std::io::Result<()> {
+        let name = CString::new(format!("/{}-sem-health", self.prefix)).unwrap();
+
+        unsafe {
+            let sem = sem_open(name.as_ptr(), O_RDWR | O_CREAT, 0o666, 1);
+
+            if sem == SEM_FAILED {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            sem_close(sem);
+            sem_unlink(name.as_ptr());
+        }
+
+        Ok(())
+    }
 
-        atomic_ptr.store(semaphore, Ordering::SeqCst);
+    fn init(prefix: &str, key: &str) -> NamedSem {
+        let name = CString::new(format!("/{prefix}-sem-{key}")).unwrap();
 
-        atomic_ptr
+        NamedSem(Self::open_semaphore(&name))
     }
 
     pub fn open_semaphore(name: &CString) -> *mut sem_t {
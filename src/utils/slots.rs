@@ -0,0 +1,66 @@
+use nix::libc::{
+    c_void, close, ftruncate, mmap, off_t, shm_open, MAP_FAILED, MAP_SHARED, O_CREAT, O_RDWR,
+    PROT_READ, PROT_WRITE,
+};
+use std::{ffi::CString, mem::size_of, ptr, sync::atomic::AtomicU64};
+
+/// Fixed number of `AtomicU64` in shared memory. Every instance sharing
+/// `/dev/shm` maps the same ones and updates them with atomic operations, so
+/// no lock is involved and the file never grows.
+pub struct Slots {
+    address: *const AtomicU64,
+    len: usize,
+}
+
+// The memory is mapped for the lifetime of the process and only ever accessed
+// through atomics.
+unsafe impl Send for Slots {}
+unsafe impl Sync for Slots {}
+
+impl Slots {
+    pub fn new(prefix: &str, name: &str, len: usize) -> Self {
+        let name = CString::new(format!("/{prefix}-{name}")).unwrap();
+        let size = len * size_of::<AtomicU64>();
+
+        unsafe {
+            let shm_fd = shm_open(name.as_ptr(), O_RDWR | O_CREAT, 0o666);
+
+            if shm_fd < 0 {
+                panic!(
+                    "failed to open shared memory with code: {}",
+                    std::io::Error::last_os_error().raw_os_error().unwrap()
+                )
+            }
+
+            // New objects are zero filled; one left by another instance keeps
+            // its values.
+            ftruncate(shm_fd, size as off_t);
+
+            let address = mmap(
+                ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                shm_fd,
+                0,
+            );
+
+            close(shm_fd);
+
+            if address == MAP_FAILED {
+                panic!("failed to map shared memory")
+            }
+
+            Self {
+                address: address as *const c_void as *const AtomicU64,
+                len,
+            }
+        }
+    }
+
+    pub fn get(&self, index: usize) -> &AtomicU64 {
+        assert!(index < self.len, "slot {index} out of {}", self.len);
+
+        unsafe { &*self.address.add(index) }
+    }
+}